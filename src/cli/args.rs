@@ -1,10 +1,159 @@
-use clap::Parser;
+use anyhow::Result;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{fs, path::Path};
+
+use crate::git::diff::ChangedLines;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BaselineAction {
+    /// Record every current violation in the baseline
+    Create,
+    /// Remove the entries of the checked files whose violations were fixed
+    Prune,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RulesAction {
+    /// Show the rules in effect for a file and where each of them was declared
+    Explain { file: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Step through the violations, accepting, editing, skipping or suppressing each of them
+    Review,
+    /// Manage the baseline of accepted violations, which are not reported
+    Baseline {
+        #[command(subcommand)]
+        action: BaselineAction,
+    },
+    /// Inspect the rules
+    Rules {
+        #[command(subcommand)]
+        action: RulesAction,
+    },
+    /// Run the examples of the rules declared in the config, flagging the rules that pass them
+    /// too rarely as ambiguous
+    TestRules {
+        /// Number of times every example is checked
+        #[arg(long, default_value_t = 3)]
+        runs: usize,
+        /// Pass rate under which a rule is ambiguous
+        #[arg(long, default_value_t = 0.8)]
+        threshold: f64,
+    },
+    /// Run a labelled dataset of rule checks against one or more configs, reporting precision,
+    /// recall, F1, latency and cost for each of them
+    Eval {
+        /// JSON list of cases, with a rule, a piece of code and the quotes of the expected
+        /// violations
+        dataset: String,
+        /// Config files to compare
+        #[arg(
+            long = "config",
+            value_name = "CONFIG",
+            default_value = "melange-config.toml"
+        )]
+        configs: Vec<String>,
+    },
+    /// Run a language server over stdio, publishing violations as diagnostics
+    Lsp,
+    /// Check the files again whenever they change
+    Watch {
+        /// Files and directories to watch
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    #[arg(short, long, value_name = "FILES")]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(short, long, value_name = "FILES", global = true)]
     pub files: Vec<String>,
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub dir: Option<String>,
+    /// Send all the rules of an item, and all the project rules of a file, in a single prompt
+    #[arg(long, global = true)]
+    pub batch: bool,
+    /// Print the prompts that would be sent, with an estimate of their cost, without sending them
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Apply the fixes suggested for the violations to the files
+    #[arg(long)]
+    pub fix: bool,
+    /// Output format of the violations and of the usage report
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub format: OutputFormat,
+    /// Stop sending requests once the run has cost this much, overriding the config
+    #[arg(long, value_name = "USD", global = true)]
+    pub max_cost_usd: Option<f64>,
+    /// Stop sending requests once this many have been sent, overriding the config
+    #[arg(long, global = true)]
+    pub max_requests: Option<usize>,
+    /// Only check the items changed since this git revision
+    #[arg(long, value_name = "REV", global = true)]
+    pub changed_since: Option<String>,
+    /// Only check the items changed in the git index
+    #[arg(long, global = true)]
+    pub staged: bool,
+}
+
+impl Cli {
+    /// The files given on the command line, followed by the Rust files found under `--dir`.
+    /// When only changes are checked, the files default to the changed Rust files, and files
+    /// without changes are left out.
+    pub fn source_files(&self, changed: Option<&ChangedLines>) -> Result<Vec<String>> {
+        let mut files = self.files.clone();
+        if let Some(dir) = &self.dir {
+            collect_rust_files(Path::new(dir), &mut files)?;
+        }
+        if let Some(changed) = changed {
+            if files.is_empty() {
+                files = changed
+                    .files()
+                    .filter(|path| path.ends_with(".rs") && Path::new(path).exists())
+                    .map(str::to_string)
+                    .collect();
+            }
+            files.retain(|path| changed.touches_file(path));
+        }
+        Ok(files)
+    }
+
+    /// The lines changed since `--changed-since` or in the index, when only changes are checked.
+    pub fn changed_lines(&self) -> Result<Option<ChangedLines>> {
+        if self.changed_since.is_none() && !self.staged {
+            return Ok(None);
+        }
+        ChangedLines::from_git(self.changed_since.as_deref(), self.staged).map(Some)
+    }
+}
+
+/// Recursively collects the `.rs` files under `dir`, skipping hidden directories and `target`.
+pub(crate) fn collect_rust_files(dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if path.is_dir() {
+            if !name.starts_with('.') && name != "target" {
+                collect_rust_files(&path, files)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
 }
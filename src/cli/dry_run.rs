@@ -0,0 +1,69 @@
+use crate::{
+    engine::{llm_engine::LlmConfig, pricing::find_price, prompt::nonce},
+    rules::generic::RuleWithCode,
+};
+
+/// Prints every prompt that a run would send, grouped by file, followed by the number of
+/// requests and their estimated token count and cost. Nothing is sent to the provider, and the
+/// prompts are shown as they would be sent, with their secrets redacted.
+pub fn run(config: &LlmConfig, files: &[(String, Vec<RuleWithCode>)]) {
+    let mut requests = 0;
+    let mut input_tokens = 0;
+    for (path, rules) in files {
+        let prompts: Vec<_> = rules.iter().flat_map(|rule| config.chunks(rule)).collect();
+        println!("==> {} ({} prompts)", path, prompts.len());
+        for prompt in prompts {
+            let prompt = config.redactor().redact(&prompt);
+            let nonce = nonce(&prompt);
+            let (system_prompt, text) = match (
+                config.system_prompt_for(&prompt),
+                config.prompt(&prompt, &nonce),
+            ) {
+                (Ok(system_prompt), Ok(text)) => (system_prompt, text),
+                (Err(e), _) | (_, Err(e)) => {
+                    eprintln!("melange: {}: {:#}", path, e);
+                    continue;
+                }
+            };
+            let tokens = config.estimate_tokens(&system_prompt) + config.estimate_tokens(&text);
+            println!(
+                "--- {} {} (line {}, ~{} tokens)",
+                prompt.item_kind(),
+                prompt.item_name(),
+                prompt.line_at(prompt.byte_range().start),
+                tokens
+            );
+            println!("{}", text);
+            requests += 1;
+            input_tokens += tokens;
+        }
+    }
+    let output_tokens = requests * config.expected_response_tokens();
+
+    println!(
+        "Dry run: {} requests, ~{} input tokens, ~{} output tokens",
+        requests, input_tokens, output_tokens
+    );
+    let configured = find_price(&config.pricing, &config.provider, config.model.as_deref());
+    if configured.is_none() {
+        println!(
+            "No price for {}/{} in the config",
+            config.provider,
+            config.model.as_deref().unwrap_or("default")
+        );
+    }
+    for price in &config.pricing {
+        let marker = if configured.is_some_and(|c| std::ptr::eq(c, price)) {
+            " (configured)"
+        } else {
+            ""
+        };
+        println!(
+            "  {}/{}: ~${:.4}{}",
+            price.provider,
+            price.model,
+            price.cost(input_tokens, output_tokens),
+            marker
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::{
+    engine::llm_engine::LlmEngine,
+    errors::melange_errors::MelangeError,
+    rules::generic::{Rule, RuleWithCode},
+};
+
+/// A labelled case of an evaluation dataset: `expected` quotes the code of every violation the
+/// model should report, and is empty when the code complies with the rule.
+#[derive(Debug, Deserialize)]
+pub struct EvalCase {
+    pub name: Option<String>,
+    pub rule: String,
+    pub code: String,
+    #[serde(default)]
+    pub expected: Vec<String>,
+}
+
+impl EvalCase {
+    /// The name of the case, or its position in the dataset, from 1.
+    fn name(&self, idx: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("case {}", idx + 1))
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct EvalReport {
+    pub config: String,
+    pub cases: usize,
+    pub errors: usize,
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+    pub requests: usize,
+    pub latency_ms: u64,
+    pub cost_usd: f64,
+    /// Why the evaluation stopped before the end of the dataset, which makes the scores those of
+    /// the cases run so far.
+    pub budget_exceeded: Option<String>,
+    /// The cases left out once the budget ran out.
+    pub not_run: Vec<String>,
+}
+
+impl EvalReport {
+    pub fn precision(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    pub fn recall(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+
+    pub fn f1(&self) -> f64 {
+        let (precision, recall) = (self.precision(), self.recall());
+        if precision + recall == 0.0 {
+            return 0.0;
+        }
+        2.0 * precision * recall / (precision + recall)
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        return 1.0;
+    }
+    part as f64 / whole as f64
+}
+
+/// Reads the cases of the dataset at `path`, checking that the code of every case holds the
+/// quotes of its expected violations.
+pub fn load_dataset(path: &str) -> Result<Vec<EvalCase>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read dataset {}", path))?;
+    let cases: Vec<EvalCase> =
+        serde_json::from_str(&content).with_context(|| format!("Malformed dataset {}", path))?;
+    for (idx, case) in cases.iter().enumerate() {
+        if let Some(quote) = case
+            .expected
+            .iter()
+            .find(|quote| !case.code.contains(quote.trim()))
+        {
+            bail!(
+                "{} of dataset {} expects a violation quoting {:?}, which is not in its code",
+                case.name(idx),
+                path,
+                quote
+            );
+        }
+    }
+    Ok(cases)
+}
+
+/// Matches the lines of the reported violations with the lines of the expected ones, each
+/// expected violation matching at most one reported violation. Returns the true positives,
+/// false positives and false negatives.
+fn score(expected: &[usize], reported: &[usize]) -> (usize, usize, usize) {
+    let mut unmatched = expected.to_vec();
+    let mut true_positives = 0;
+    for line in reported {
+        if let Some(idx) = unmatched.iter().position(|expected| expected == line) {
+            unmatched.swap_remove(idx);
+            true_positives += 1;
+        }
+    }
+    (
+        true_positives,
+        reported.len() - true_positives,
+        unmatched.len(),
+    )
+}
+
+/// Runs every case of `dataset` against `llm`, whose config was loaded from `config_path`,
+/// until its budget runs out.
+pub async fn eval(config_path: &str, llm: &LlmEngine, dataset: &[EvalCase]) -> EvalReport {
+    let mut report = EvalReport {
+        config: config_path.to_string(),
+        cases: dataset.len(),
+        ..Default::default()
+    };
+    for (i, case) in dataset.iter().enumerate() {
+        let name = case.name(i);
+        let snippet = RuleWithCode::snippet(Rule::new(&case.rule), name.clone(), &case.code);
+        let expected: Vec<_> = case
+            .expected
+            .iter()
+            .map(|quote| {
+                let offset = case
+                    .code
+                    .find(quote.trim())
+                    .expect("quotes are checked when the dataset is loaded");
+                snippet.line_at(offset)
+            })
+            .collect();
+        match llm.query_with_rule(&snippet).await {
+            Ok(violations) => {
+                let reported: Vec<_> = violations.iter().map(|v| v.line).collect();
+                let (tp, fp, fn_) = score(&expected, &reported);
+                report.true_positives += tp;
+                report.false_positives += fp;
+                report.false_negatives += fn_;
+            }
+            Err(e) => match e.downcast::<MelangeError>() {
+                Ok(MelangeError::BudgetExceeded { reason, .. }) => {
+                    // The case that hit the limit is left out with the rest, rather than scored
+                    // on what it found so far.
+                    report.budget_exceeded = Some(reason);
+                    report.not_run = (i..dataset.len())
+                        .map(|idx| dataset[idx].name(idx))
+                        .collect();
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("melange: {}: {}: {:#}", config_path, name, e);
+                    report.errors += 1;
+                    report.false_negatives += expected.len();
+                }
+            },
+        }
+    }
+    let usage = llm.usage_report().total;
+    report.requests = usage.requests;
+    report.latency_ms = usage.latency_ms;
+    report.cost_usd = usage.cost_usd;
+    report
+}
+
+pub fn print_reports(reports: &[EvalReport]) {
+    println!("Evaluation (costs are estimates)");
+    println!(
+        "{:<32} {:>6} {:>7} {:>10} {:>8} {:>6} {:>12} {:>10}",
+        "config", "cases", "errors", "precision", "recall", "F1", "avg latency", "cost"
+    );
+    for report in reports {
+        let avg_latency = report.latency_ms / report.requests.max(1) as u64;
+        println!(
+            "{:<32} {:>6} {:>7} {:>10.2} {:>8.2} {:>6.2} {:>10}ms {:>10.4}",
+            report.config,
+            report.cases,
+            report.errors,
+            report.precision(),
+            report.recall(),
+            report.f1(),
+            avg_latency,
+            report.cost_usd
+        );
+    }
+    for report in reports {
+        if let Some(reason) = &report.budget_exceeded {
+            eprintln!(
+                "melange: {}: budget exceeded ({}), the scores are incomplete, {} cases not run: {}",
+                report.config,
+                reason,
+                report.not_run.len(),
+                report.not_run.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::mock::{self, MockResponse},
+        fixture::Fixture,
+    };
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score(&[2, 5], &[2, 2, 7]), (1, 2, 1));
+        let report = EvalReport {
+            true_positives: 3,
+            false_positives: 1,
+            false_negatives: 3,
+            ..Default::default()
+        };
+        assert_eq!(report.precision(), 0.75);
+        assert_eq!(report.recall(), 0.5);
+        assert_eq!(report.f1(), 0.6);
+    }
+
+    #[test]
+    fn test_load_dataset() {
+        let fixture = Fixture::new(&[(
+            "dataset.json",
+            br#"[
+                {"rule": "no unwrap", "code": "fn a() { x.unwrap(); }", "expected": ["x.unwrap()"]},
+                {"name": "typo", "rule": "no unwrap", "code": "fn b() { y.unwrap(); }", "expected": ["x.unwrap()"]}
+            ]"#,
+        )]);
+        let path = fixture.path().join("dataset.json");
+        let e = load_dataset(path.to_str().unwrap()).unwrap_err();
+        assert!(e.to_string().starts_with("typo of dataset"), "{}", e);
+        assert!(e.to_string().contains(r#""x.unwrap()""#));
+        assert!(load_dataset("eval/dataset.json").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_eval_budget() {
+        let dataset: Vec<EvalCase> = serde_json::from_str(
+            r#"[
+                {"rule": "no unwrap", "code": "fn a() {\n    x.unwrap();\n}", "expected": ["x.unwrap()"]},
+                {"rule": "no unwrap", "code": "fn b() {\n    y.unwrap();\n}", "expected": ["y.unwrap()"]},
+                {"name": "clean", "rule": "no unwrap", "code": "fn c() {}"}
+            ]"#,
+        )
+        .unwrap();
+        let llm = mock::engine(
+            mock::config("max_requests = 1"),
+            &[MockResponse::Text(
+                r#"{"violations": [{"quote": "x.unwrap()", "explanation": "panics"}]}"#.to_string(),
+            )],
+        );
+        let report = eval("melange-config.toml", &llm, &dataset).await;
+        // The cases past the limit are neither errors nor missed violations.
+        assert_eq!(
+            report.budget_exceeded.as_deref(),
+            Some("1 requests sent, limit is 1")
+        );
+        assert_eq!(report.not_run, ["case 2", "clean"]);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.true_positives, 1);
+        assert_eq!(report.false_negatives, 0);
+    }
+}
@@ -0,0 +1,80 @@
+use anyhow::{Context, Result};
+use std::fs;
+
+use crate::{
+    config::project_rules::ProjectRules,
+    rules::{generic::extract_rule_map, scope::ProjectRule},
+};
+
+fn target(rule: &ProjectRule) -> String {
+    let scope = &rule.scope;
+    if scope.is_file_level() {
+        return "the whole file".to_string();
+    }
+    let mut targets = Vec::new();
+    if !scope.item_kinds.is_empty() {
+        targets.push(format!("items of kind {}", scope.item_kinds.join(", ")));
+    }
+    if let Some(selector) = &scope.selector {
+        targets.push(format!("items selected by `{}`", selector.source()));
+    }
+    targets.join(" and ")
+}
+
+/// Prints the rules in effect for `file`: the project rules with where each one was declared,
+/// the disabled ones, and the rules annotating its items.
+pub fn explain(file: &str, project_rules: &ProjectRules) -> Result<()> {
+    let effective = project_rules.for_file(file)?;
+    let (applying, skipped): (Vec<_>, Vec<_>) = effective
+        .rules
+        .iter()
+        .partition(|rule| rule.scope.matches_file(file, "rust"));
+
+    println!("Rules in effect for {}", file);
+    println!("\nProject rules:");
+    for rule in &applying {
+        println!(
+            "  [{}] {} ({})",
+            rule.rule.id(),
+            rule.rule,
+            rule.rule.severity()
+        );
+        println!(
+            "      from {}, checked against {}",
+            rule.source,
+            target(rule)
+        );
+        if !rule.context.is_empty() {
+            println!("      with code from {}", rule.context.join(", "));
+        }
+        if rule.tools {
+            println!("      with tool calls over the crate");
+        }
+    }
+    if !skipped.is_empty() {
+        println!("\nProject rules whose paths or languages leave this file out:");
+        for rule in &skipped {
+            println!("  [{}] {}", rule.rule.id(), rule.rule);
+            println!("      from {}", rule.source);
+        }
+    }
+    if !effective.disabled.is_empty() {
+        println!("\nDisabled rules:");
+        for disabled in &effective.disabled {
+            println!("  [{}] disabled by {}", disabled.id, disabled.source);
+        }
+    }
+
+    let content = fs::read_to_string(file).with_context(|| format!("Failed to read {}", file))?;
+    let mut annotations: Vec<_> = extract_rule_map(&content).into_iter().collect();
+    annotations.sort_by_key(|(line, _)| *line);
+    if !annotations.is_empty() {
+        println!("\nAnnotated items:");
+        for (line, rules) in annotations {
+            for rule in rules {
+                println!("  {}:{} [{}] {}", file, line, rule.id(), rule);
+            }
+        }
+    }
+    Ok(())
+}
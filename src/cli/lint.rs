@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::{collections::HashSet, fs, sync::Arc};
+
+use crate::{
+    config::project_rules::ProjectRules,
+    engine::llm_engine::LlmEngine,
+    errors::melange_errors::MelangeError,
+    git::diff::ChangedLines,
+    parser::rust_parser::parse_rust_source,
+    rules::{
+        generic::{RuleWithCode, normalize_path},
+        suppression::{SuppressionReport, apply_suppressions},
+        violation::Violation,
+    },
+};
+
+/// The rule checks to run on a file: its annotated items and the project rules, minus the
+/// suppressed ones and, when only changes are checked, the untouched ones.
+pub fn file_rules(
+    path: &str,
+    project_rules: &ProjectRules,
+    batch: bool,
+    changed: Option<&ChangedLines>,
+) -> Result<(Vec<RuleWithCode>, SuppressionReport)> {
+    debug!("Parsing file: {}", path);
+    let content =
+        Arc::new(fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?);
+    let rules = parse_rust_source(
+        path,
+        Arc::clone(&content),
+        &project_rules.for_file(path)?.rules,
+        project_rules.context_sources(),
+    )?;
+    let (mut rules, suppressions) = apply_suppressions(path, &content, rules);
+    if let Some(changed) = changed {
+        rules.retain(|rule| changed.touches(rule));
+    }
+    if batch {
+        rules = RuleWithCode::batch(rules, |rule| project_rules.has_own_template(rule));
+    }
+    Ok((rules, suppressions))
+}
+
+#[derive(Debug, Default)]
+pub struct LintOutcome<'a> {
+    pub violations: Vec<Violation>,
+    /// Why the run stopped early, if it hit one of its budget limits.
+    pub budget_exceeded: Option<String>,
+    pub not_evaluated: Vec<&'a RuleWithCode>,
+    /// Rule checks whose query failed, such as on a malformed response.
+    pub failed: Vec<&'a RuleWithCode>,
+    /// Rule checks the model gave its verdict on.
+    pub evaluated: Vec<&'a RuleWithCode>,
+}
+
+impl LintOutcome<'_> {
+    /// The files and ids of the rules that were fully checked in each of them.
+    pub fn evaluated_rules(&self) -> HashSet<(String, String)> {
+        self.evaluated
+            .iter()
+            .flat_map(|rule| {
+                rule.rules().iter().map(|r| {
+                    (
+                        normalize_path(rule.file_name()).to_string(),
+                        r.id().to_string(),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// Checks the rules of every file, stopping once the budget of the engine is spent.
+pub async fn lint<'a>(
+    llm: &LlmEngine,
+    files: &'a [(String, Vec<RuleWithCode>)],
+) -> LintOutcome<'a> {
+    let mut outcome = LintOutcome::default();
+    for (path, rules) in files {
+        debug!("Checking file: {}", path);
+        for rule in rules {
+            if outcome.budget_exceeded.is_some() {
+                outcome.not_evaluated.push(rule);
+                continue;
+            }
+            match llm.query_with_rule(rule).await {
+                Ok(found) => {
+                    outcome.violations.extend(found);
+                    outcome.evaluated.push(rule);
+                }
+                Err(e) => match e.downcast::<MelangeError>() {
+                    Ok(MelangeError::BudgetExceeded { reason, found }) => {
+                        // The rule is reported as not evaluated, along with what it found.
+                        outcome.violations.extend(found);
+                        outcome.budget_exceeded = Some(reason);
+                        outcome.not_evaluated.push(rule);
+                    }
+                    Err(e) => {
+                        eprintln!("melange: {}: {:#}", path, e);
+                        outcome.failed.push(rule);
+                    }
+                },
+            }
+        }
+    }
+    outcome
+}
@@ -1 +1,8 @@
-pub mod args;
\ No newline at end of file
+pub mod args;
+pub mod dry_run;
+pub mod eval;
+pub mod explain;
+pub mod lint;
+pub mod review;
+pub mod test_rules;
+pub mod watch;
@@ -0,0 +1,284 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    io::{self, BufRead, Write},
+    process::Command,
+};
+
+use crate::{
+    fix::apply::fix_content,
+    rules::violation::{Fix, Violation},
+};
+
+/// Lines of code shown around a violation.
+const CONTEXT_LINES: usize = 2;
+
+enum Decision {
+    Accept(Fix),
+    Skip,
+    Suppress(String),
+    Quit,
+}
+
+/// Steps through `violations` one at a time, asking whether to accept, edit, skip or suppress
+/// each of them. Accepted fixes and suppression comments are written to the files at the end.
+pub fn review(violations: Vec<Violation>) -> Result<()> {
+    let mut contents: HashMap<String, String> = HashMap::new();
+    let mut edits: BTreeMap<String, Vec<Violation>> = BTreeMap::new();
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let total = violations.len();
+
+    for (idx, violation) in violations.into_iter().enumerate() {
+        if !contents.contains_key(&violation.file_name) {
+            let content = fs::read_to_string(&violation.file_name)
+                .with_context(|| format!("Failed to read {}", violation.file_name))?;
+            contents.insert(violation.file_name.clone(), content);
+        }
+        let content = &contents[&violation.file_name];
+
+        println!(
+            "\n[{}/{}] {}:{}",
+            idx + 1,
+            total,
+            violation.file_name,
+            violation.line
+        );
+        println!("rule: {}", violation.rule);
+        print_context(content, violation.line);
+        println!("{}", violation.explanation);
+        if let Some(fix) = &violation.fix {
+            print_fix(content, fix);
+        }
+
+        let edit = match ask(&mut input, content, &violation)? {
+            Decision::Accept(fix) => Some(fix),
+            Decision::Suppress(reason) => Some(suppression(content, &violation, &reason)),
+            Decision::Skip => None,
+            Decision::Quit => break,
+        };
+        if let Some(fix) = edit {
+            edits
+                .entry(violation.file_name.clone())
+                .or_default()
+                .push(Violation {
+                    fix: Some(fix),
+                    ..violation
+                });
+        }
+    }
+
+    for (path, violations) in edits {
+        let (fixed, report) = fix_content(&contents[&path], violations);
+        if !report.applied.is_empty() {
+            fs::write(&path, fixed)?;
+        }
+        println!(
+            "{}: {} edits written, {} duplicates, {} overlapping, {} rolled back as they broke parsing",
+            path,
+            report.applied.len(),
+            report.duplicates.len(),
+            report.conflicting.len(),
+            report.rolled_back.len()
+        );
+    }
+    Ok(())
+}
+
+fn ask(input: &mut impl BufRead, content: &str, violation: &Violation) -> Result<Decision> {
+    loop {
+        let options = if violation.fix.is_some() {
+            "[a]ccept fix, [e]dit, [s]kip, s[u]ppress, [q]uit"
+        } else {
+            "[e]dit, [s]kip, s[u]ppress, [q]uit"
+        };
+        let answer = prompt(input, &format!("{} > ", options))?;
+        match answer.as_str() {
+            "a" if violation.fix.is_some() => {
+                return Ok(Decision::Accept(violation.fix.clone().unwrap()));
+            }
+            "e" => match editable_range(content, violation) {
+                Some(byte_range) => {
+                    let current = violation
+                        .fix
+                        .as_ref()
+                        .map_or(&content[byte_range.clone()], |fix| &fix.replacement);
+                    let replacement = edit_in_editor(current)?;
+                    return Ok(Decision::Accept(Fix {
+                        byte_range,
+                        replacement,
+                    }));
+                }
+                None => println!("The quoted code was not found in the file, it cannot be edited"),
+            },
+            "s" | "" => return Ok(Decision::Skip),
+            "u" => {
+                let reason = prompt(input, "reason > ")?;
+                if reason.is_empty() {
+                    println!("A suppression needs a reason");
+                } else {
+                    return Ok(Decision::Suppress(reason));
+                }
+            }
+            "q" => return Ok(Decision::Quit),
+            _ => {}
+        }
+    }
+}
+
+fn prompt(input: &mut impl BufRead, message: &str) -> Result<String> {
+    print!("{}", message);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok("q".to_string());
+    }
+    Ok(line.trim().to_string())
+}
+
+fn print_context(content: &str, line: usize) {
+    let first = line.saturating_sub(CONTEXT_LINES).max(1);
+    for (number, text) in content
+        .lines()
+        .enumerate()
+        .map(|(i, text)| (i + 1, text))
+        .skip(first - 1)
+        .take(line + CONTEXT_LINES + 1 - first)
+    {
+        let marker = if number == line { ">" } else { " " };
+        println!("{} {:>5} | {}", marker, number, text);
+    }
+}
+
+fn print_fix(content: &str, fix: &Fix) {
+    println!("Suggested fix:");
+    for line in content[fix.byte_range.clone()].lines() {
+        println!("- {}", line);
+    }
+    for line in fix.replacement.lines() {
+        println!("+ {}", line);
+    }
+}
+
+/// The span an edit replaces: that of the suggested fix, or the quoted code.
+fn editable_range(content: &str, violation: &Violation) -> Option<std::ops::Range<usize>> {
+    if let Some(fix) = &violation.fix {
+        return Some(fix.byte_range.clone());
+    }
+    let quote = violation.quote.trim();
+    content
+        .get(violation.byte_offset..)?
+        .starts_with(quote)
+        .then(|| violation.byte_offset..violation.byte_offset + quote.len())
+}
+
+fn edit_in_editor(text: &str) -> Result<String> {
+    let path = env::temp_dir().join(format!("melange-review-{}.rs", std::process::id()));
+    fs::write(&path, text)?;
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // Run through the shell, as git does, so that the editor can carry arguments such as
+    // `code --wait`.
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(&path)
+        .status()?;
+    anyhow::ensure!(status.success(), "{} exited with {}", editor, status);
+    let edited = fs::read_to_string(&path)?;
+    fs::remove_file(&path)?;
+    Ok(edited.trim_end_matches('\n').to_string())
+}
+
+/// An insertion of a suppression comment above the violating item, or at the top of the file
+/// for project rules.
+fn suppression(content: &str, violation: &Violation, reason: &str) -> Fix {
+    let rule = &violation.rule_id;
+    let (line, comment) = if violation.item_kind == "file" {
+        (1, format!("// melange-allow-file({}): {}", rule, reason))
+    } else {
+        (
+            violation.item_line,
+            format!("// melange-allow({}): {}", rule, reason),
+        )
+    };
+    let offset: usize = content
+        .split_inclusive('\n')
+        .take(line - 1)
+        .map(str::len)
+        .sum();
+    let indent: String = content[offset..]
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect();
+    Fix {
+        byte_range: offset..offset,
+        replacement: format!("{}{}\n", indent, comment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::suppression::is_suppression;
+
+    #[test]
+    fn test_edits() {
+        let content = "impl Db {\n    fn add(&self) {\n        x.unwrap();\n    }\n}\n";
+        let mut violation = Violation::test("no-unwrap", "a.rs", content, "x.unwrap()");
+        violation.item_line = 2;
+
+        // The comment goes above the item, at its indentation.
+        let fix = suppression(content, &violation, "checked above");
+        let (suppressed, _) = fix_content(
+            content,
+            vec![Violation {
+                fix: Some(fix),
+                ..violation.clone()
+            }],
+        );
+        assert_eq!(
+            suppressed,
+            "impl Db {\n    // melange-allow(no-unwrap): checked above\n    fn add(&self) {\n        x.unwrap();\n    }\n}\n"
+        );
+        assert!(is_suppression(suppressed.lines().nth(1).unwrap()));
+        let file_level = Violation {
+            item_kind: "file".to_string(),
+            ..violation.clone()
+        };
+        let fix = suppression(content, &file_level, "legacy");
+        assert_eq!(fix.byte_range, 0..0);
+        assert_eq!(
+            fix.replacement,
+            "// melange-allow-file(no-unwrap): legacy\n"
+        );
+
+        // An edit replaces the quoted code, or the code of the suggested fix.
+        let start = content.find("x.unwrap()").unwrap();
+        assert_eq!(
+            editable_range(content, &violation),
+            Some(start..start + "x.unwrap()".len())
+        );
+        let fix = Fix {
+            byte_range: start..start + "x.unwrap();".len(),
+            replacement: "x?;".to_string(),
+        };
+        let with_fix = Violation {
+            fix: Some(fix),
+            ..violation.clone()
+        };
+        assert_eq!(
+            editable_range(content, &with_fix),
+            Some(start..start + "x.unwrap();".len())
+        );
+        // A quote that is not where the violation points cannot be edited.
+        let moved = Violation {
+            byte_offset: 0,
+            ..violation
+        };
+        assert_eq!(editable_range(content, &moved), None);
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::Result;
+
+use crate::{
+    config::rule_config::RuleConfig,
+    engine::llm_engine::LlmEngine,
+    errors::melange_errors::MelangeError,
+    rules::generic::{Rule, RuleWithCode},
+};
+
+/// How often a rule judged its examples as expected.
+pub struct RuleTestResult {
+    pub rule: Rule,
+    pub examples: usize,
+    pub checks: usize,
+    pub passed: usize,
+    /// Checks that failed, such as on a malformed response, which count as not passed.
+    pub errors: usize,
+}
+
+impl RuleTestResult {
+    pub fn pass_rate(&self) -> f64 {
+        if self.checks == 0 {
+            return 1.0;
+        }
+        self.passed as f64 / self.checks as f64
+    }
+}
+
+#[derive(Default)]
+pub struct RuleTestOutcome {
+    pub results: Vec<RuleTestResult>,
+    /// Why the run stopped before checking every example, if it did.
+    pub budget_exceeded: Option<String>,
+    /// The examples not checked `runs` times once the budget ran out, as `<rule id>: <example>`.
+    pub not_run: Vec<String>,
+}
+
+/// Checks every example of `rules` `runs` times: `should_pass` examples pass when no violation
+/// is found, `should_fail` examples when at least one is. Once the budget runs out, the
+/// remaining examples are left out.
+pub async fn test_rules(
+    llm: &LlmEngine,
+    rules: &[RuleConfig],
+    runs: usize,
+) -> Result<RuleTestOutcome> {
+    let mut outcome = RuleTestOutcome::default();
+    for config in rules {
+        let rule = config.rule();
+        let examples = config
+            .should_pass
+            .iter()
+            .enumerate()
+            .map(|(i, code)| {
+                (
+                    RuleWithCode::snippet(rule.clone(), format!("should_pass[{}]", i), code),
+                    false,
+                )
+            })
+            .chain(config.should_fail.iter().enumerate().map(|(i, code)| {
+                (
+                    RuleWithCode::snippet(rule.clone(), format!("should_fail[{}]", i), code),
+                    true,
+                )
+            }))
+            .collect::<Vec<_>>();
+        let mut result = RuleTestResult {
+            rule: rule.clone(),
+            examples: examples.len(),
+            checks: 0,
+            passed: 0,
+            errors: 0,
+        };
+        for (example, should_fail) in &examples {
+            let name = format!("{}: {}", rule.id(), example.item_name());
+            if outcome.budget_exceeded.is_some() {
+                outcome.not_run.push(name);
+                continue;
+            }
+            for _ in 0..runs {
+                match llm.query_with_rule(example).await {
+                    Ok(violations) => {
+                        result.checks += 1;
+                        if violations.is_empty() != *should_fail {
+                            result.passed += 1;
+                        }
+                    }
+                    Err(e) => match e.downcast::<MelangeError>() {
+                        // Running out of budget says nothing of the rule, so it is not counted.
+                        Ok(MelangeError::BudgetExceeded { reason, .. }) => {
+                            outcome.budget_exceeded = Some(reason);
+                            outcome.not_run.push(name);
+                            break;
+                        }
+                        Err(e) => {
+                            eprintln!("melange: {}: {:#}", name, e);
+                            result.checks += 1;
+                            result.errors += 1;
+                        }
+                    },
+                }
+            }
+        }
+        if result.checks > 0 || outcome.budget_exceeded.is_none() {
+            outcome.results.push(result);
+        }
+    }
+    Ok(outcome)
+}
+
+/// Prints the pass rate of every rule checked and the examples left out, and returns whether the
+/// rules all reach `threshold`.
+pub fn print_results(outcome: &RuleTestOutcome, threshold: f64) -> bool {
+    println!(
+        "{:<12} {:<48} {:>8} {:>8} {:>8} {:>10}",
+        "id", "rule", "examples", "checks", "errors", "pass rate"
+    );
+    let mut all_pass = true;
+    for result in &outcome.results {
+        let description: String = result.rule.description().chars().take(48).collect();
+        let ambiguous = result.pass_rate() < threshold;
+        all_pass &= !ambiguous;
+        println!(
+            "{:<12} {:<48} {:>8} {:>8} {:>8} {:>9.0}%{}",
+            result.rule.id(),
+            description,
+            result.examples,
+            result.checks,
+            result.errors,
+            result.pass_rate() * 100.0,
+            if ambiguous { "  ambiguous" } else { "" }
+        );
+    }
+    if let Some(reason) = &outcome.budget_exceeded {
+        eprintln!(
+            "melange: budget exceeded ({}), {} examples not run:",
+            reason,
+            outcome.not_run.len()
+        );
+        for name in &outcome.not_run {
+            eprintln!("  {}", name);
+        }
+    }
+    all_pass
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::mock::{self, MockResponse};
+
+    #[tokio::test]
+    async fn test_failed_checks() {
+        let mut config = mock::config(
+            r#"
+            [[rules]]
+            id = "no-unwrap"
+            description = "no unwrap"
+            should_pass = ["fn a(x: Option<i32>) -> i32 { x.unwrap_or(0) }"]
+            should_fail = ["fn b(x: Option<i32>) -> i32 { x.unwrap() }"]
+            "#,
+        );
+        let rules = std::mem::take(&mut config.rules);
+        let llm = mock::engine(
+            config,
+            &[
+                MockResponse::Error("connection reset".to_string()),
+                MockResponse::Text(
+                    r#"{"violations": [{"quote": "x.unwrap()", "explanation": "unwrap"}]}"#
+                        .to_string(),
+                ),
+            ],
+        );
+        // The failed check counts against the rule, and the other ones still run.
+        let results = test_rules(&llm, &rules, 1).await.unwrap().results;
+        assert_eq!(results[0].checks, 2);
+        assert_eq!(results[0].errors, 1);
+        assert_eq!(results[0].passed, 1);
+        assert_eq!(results[0].pass_rate(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_budget_exceeded() {
+        let mut config = mock::config(
+            r#"
+            max_requests = 1
+
+            [[rules]]
+            id = "no-unwrap"
+            description = "no unwrap"
+            should_pass = ["fn a(x: Option<i32>) -> i32 { x.unwrap_or(0) }"]
+            should_fail = ["fn b(x: Option<i32>) -> i32 { x.unwrap() }"]
+
+            [[rules]]
+            id = "no-expect"
+            description = "no expect"
+            should_fail = ["fn c(x: Option<i32>) -> i32 { x.expect(\"c\") }"]
+            "#,
+        );
+        let rules = std::mem::take(&mut config.rules);
+        let llm = mock::engine(
+            config,
+            &[MockResponse::Text(r#"{"violations": []}"#.to_string())],
+        );
+        // The checks past the limit count neither for nor against the rules.
+        let outcome = test_rules(&llm, &rules, 2).await.unwrap();
+        assert_eq!(
+            outcome.budget_exceeded.as_deref(),
+            Some("1 requests sent, limit is 1")
+        );
+        assert_eq!(
+            outcome.not_run,
+            [
+                "no-unwrap: should_pass[0]",
+                "no-unwrap: should_fail[0]",
+                "no-expect: should_fail[0]"
+            ]
+        );
+        assert_eq!(outcome.results.len(), 1);
+        assert_eq!(outcome.results[0].checks, 1);
+        assert_eq!(outcome.results[0].pass_rate(), 1.0);
+    }
+}
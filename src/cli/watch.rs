@@ -0,0 +1,383 @@
+use anyhow::Result;
+use log::{debug, error};
+use notify::{Event, RecursiveMode, Watcher};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::{
+    cli::{
+        args::collect_rust_files,
+        lint::{LintOutcome, file_rules, lint},
+    },
+    config::project_rules::{PROJECT_RULES_FILE, ProjectRules, rule_dirs},
+    engine::{cache::CACHE_FILE, llm_engine::LlmEngine},
+    rules::{generic::normalize_path, suppression::SuppressionReport, violation::Violation},
+};
+
+/// Quiet time after the last change before the changed files are checked again.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// What the last check of a file found.
+struct Checked {
+    violations: Vec<Violation>,
+    suppressions: SuppressionReport,
+    /// Why the rule checks of the file were not all evaluated, if the budget ran out.
+    budget_exceeded: Option<String>,
+    not_evaluated: usize,
+    failed: usize,
+}
+
+impl Checked {
+    /// The result of `outcome`, where the rule checks that got no verdict, for want of budget or
+    /// on a failed request, keep the violations they had in the `previous` check of the file.
+    fn new(
+        outcome: LintOutcome,
+        suppressions: SuppressionReport,
+        previous: Option<&Checked>,
+    ) -> Self {
+        let mut violations = outcome.violations;
+        let unchecked: Vec<_> = outcome
+            .not_evaluated
+            .iter()
+            .chain(&outcome.failed)
+            .collect();
+        let kept: Vec<_> = previous
+            .into_iter()
+            .flat_map(|previous| &previous.violations)
+            .filter(|old| {
+                unchecked.iter().any(|rule| {
+                    rule.item_path() == old.item_path
+                        && rule.rules().iter().any(|r| r.id() == old.rule_id)
+                }) && !violations.iter().any(|v| v.fingerprint == old.fingerprint)
+            })
+            .cloned()
+            .collect();
+        violations.extend(kept);
+        Self {
+            violations,
+            suppressions,
+            budget_exceeded: outcome.budget_exceeded,
+            not_evaluated: outcome.not_evaluated.len(),
+            failed: outcome.failed.len(),
+        }
+    }
+}
+
+/// Checks the Rust files under `paths`, then checks every file again whenever it or one of the
+/// rule files that apply to it changes, and redraws the violations. Only the items whose code
+/// changed are sent again, the others are answered from the engine's cache.
+pub async fn run(
+    llm: &LlmEngine,
+    paths: &[String],
+    project_rules: &ProjectRules,
+    batch: bool,
+) -> Result<()> {
+    // The watcher sends from its own thread; the channel lets the runtime wait without blocking.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    let mut files = Vec::new();
+    let mut watched = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        watcher.watch(path, RecursiveMode::Recursive)?;
+        if path.is_dir() {
+            collect_rust_files(path, &mut files)?;
+            watched.push(path.canonicalize()?);
+        } else {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    // The rule files of the directories above the watched paths apply to their files too.
+    let outer_dirs: BTreeSet<_> = paths
+        .iter()
+        .flat_map(|path| rule_dirs(path))
+        .filter_map(|dir| Path::new(".").join(dir).canonicalize().ok())
+        .filter(|dir| !watched.iter().any(|path| dir.starts_with(path)))
+        .collect();
+    for dir in outer_dirs {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+
+    let mut checked: BTreeMap<String, Checked> = BTreeMap::new();
+    for file in files {
+        check(
+            llm,
+            &key(Path::new(&file)),
+            project_rules,
+            batch,
+            &mut checked,
+        )
+        .await;
+    }
+    redraw(&checked);
+
+    while let Some(changed) = next_changes(&mut rx, DEBOUNCE).await {
+        let mut files = changed_files(&changed);
+        for dir in changed_rule_dirs(&changed) {
+            files.extend(
+                checked
+                    .keys()
+                    .filter(|file| Path::new(file).starts_with(&dir))
+                    .cloned(),
+            );
+        }
+        files.sort();
+        files.dedup();
+        if files.is_empty() {
+            continue;
+        }
+        for file in files {
+            // Rules relating other files to the checked ones see the new code.
+            project_rules.context_sources().invalidate(Path::new(&file));
+            if Path::new(&file).exists() {
+                check(llm, &file, project_rules, batch, &mut checked).await;
+            } else {
+                checked.remove(&file);
+            }
+        }
+        if let Err(e) = llm.save_cache(CACHE_FILE) {
+            error!("Failed to save the cache: {:#}", e);
+        }
+        redraw(&checked);
+    }
+    Ok(())
+}
+
+/// Waits for the next change, then lets a burst of saves settle for `debounce` before returning
+/// every path touched by it. Returns `None` once the watcher is gone.
+async fn next_changes(
+    rx: &mut UnboundedReceiver<notify::Result<Event>>,
+    debounce: Duration,
+) -> Option<BTreeSet<PathBuf>> {
+    let mut changed = BTreeSet::new();
+    let mut event = Some(rx.recv().await?);
+    while let Some(next) = event {
+        match next {
+            Ok(event) if !event.kind.is_access() => changed.extend(event.paths),
+            Ok(_) => {}
+            Err(e) => error!("Watch error: {}", e),
+        }
+        event = tokio::time::timeout(debounce, rx.recv())
+            .await
+            .ok()
+            .flatten();
+    }
+    Some(changed)
+}
+
+/// The Rust files among the `changed` paths, by their key, leaving out build outputs and hidden
+/// directories.
+fn changed_files(changed: &BTreeSet<PathBuf>) -> Vec<String> {
+    changed
+        .iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .map(|path| key(path))
+        .filter(|path| {
+            !Path::new(path).components().any(|c| {
+                let c = c.as_os_str().to_string_lossy();
+                c == "target" || (c.starts_with('.') && c != "." && c != "..")
+            })
+        })
+        .collect()
+}
+
+/// The directories of the rule files among the `changed` paths, by their key, empty for the
+/// working directory.
+fn changed_rule_dirs(changed: &BTreeSet<PathBuf>) -> Vec<String> {
+    changed
+        .iter()
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name == PROJECT_RULES_FILE)
+        })
+        .filter_map(|path| path.parent())
+        .map(key)
+        .collect()
+}
+
+async fn check(
+    llm: &LlmEngine,
+    file: &str,
+    project_rules: &ProjectRules,
+    batch: bool,
+    checked: &mut BTreeMap<String, Checked>,
+) {
+    debug!("Checking {}", file);
+    let (rules, suppressions) = match file_rules(file, project_rules, batch, None) {
+        Ok(found) => found,
+        Err(e) => {
+            // Keep the last violations until the file parses again.
+            error!("{:#}", e);
+            return;
+        }
+    };
+    let files = [(file.to_string(), rules)];
+    let outcome = lint(llm, &files).await;
+    let result = Checked::new(outcome, suppressions, checked.get(file));
+    checked.insert(file.to_string(), result);
+}
+
+/// The key of a file in the checked files, and the path it is checked under: relative to the
+/// working directory, since paths from the watcher are absolute, and without a leading `./`,
+/// since those from the first pass have one.
+fn key(path: &Path) -> String {
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned();
+    normalize_path(&relative).to_string()
+}
+
+fn redraw(checked: &BTreeMap<String, Checked>) {
+    // Clear the screen and move the cursor to the top left.
+    print!("\x1b[2J\x1b[H");
+    for violation in checked.values().flat_map(|file| &file.violations) {
+        println!("{}\n", violation);
+    }
+    for (path, file) in checked {
+        file.suppressions.print_warnings();
+        if let Some(reason) = &file.budget_exceeded {
+            println!(
+                "melange: {}: budget exceeded ({}), {} rule checks not evaluated, showing their last violations",
+                path, reason, file.not_evaluated
+            );
+        }
+        if file.failed > 0 {
+            println!(
+                "melange: {}: {} rule checks failed, showing their last violations",
+                path, file.failed
+            );
+        }
+    }
+    println!(
+        "melange: watching {} files, {} violations",
+        checked.len(),
+        checked
+            .values()
+            .map(|file| file.violations.len())
+            .sum::<usize>()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::generic::{Rule, RuleWithCode};
+    use notify::EventKind;
+    use notify::event::{AccessKind, ModifyKind};
+
+    #[test]
+    fn test_keys() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(key(Path::new("./src/main.rs")), "src/main.rs");
+        assert_eq!(key(&cwd.join("src/main.rs")), "src/main.rs");
+        assert_eq!(
+            changed_files(&BTreeSet::from([
+                cwd.join("src/main.rs"),
+                cwd.join("target/debug/build/out.rs"),
+                cwd.join(".git/hooks.rs"),
+                cwd.join("README.md"),
+            ])),
+            ["src/main.rs"]
+        );
+        assert_eq!(
+            changed_rule_dirs(&BTreeSet::from([
+                cwd.join(".melangerules"),
+                cwd.join("src/.melangerules"),
+                cwd.join("src/main.rs"),
+            ])),
+            ["", "src"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debounce() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let event = |kind, path: &str| Ok(Event::new(kind).add_path(PathBuf::from(path)));
+        let modify = EventKind::Modify(ModifyKind::Any);
+        tx.send(event(modify, "a.rs")).unwrap();
+        tx.send(event(EventKind::Access(AccessKind::Any), "b.rs"))
+            .unwrap();
+        tx.send(event(modify, "c.rs")).unwrap();
+        let late = tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            late.send(event(modify, "d.rs")).unwrap();
+        });
+
+        // The burst ends once no event came for the debounce time.
+        let burst = next_changes(&mut rx, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(burst, BTreeSet::from(["a.rs".into(), "c.rs".into()]));
+        let next = next_changes(&mut rx, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(next, BTreeSet::from(["d.rs".into()]));
+        drop(tx);
+        assert!(
+            next_changes(&mut rx, Duration::from_millis(50))
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_unchecked_keep_violations() {
+        let content = "fn add(a: i32) -> i32 {\n    a.checked_add(1).unwrap()\n}\n";
+        let rule = |id: &str| {
+            RuleWithCode::new(
+                Rule::with_id(id, id),
+                "a.rs".to_string(),
+                std::sync::Arc::new(content.to_string()),
+                "function".to_string(),
+                "add".to_string(),
+                0..content.len() - 1,
+            )
+        };
+        let (no_unwrap, no_overflow) = (rule("no-unwrap"), rule("no-overflow"));
+        let previous = Checked {
+            violations: vec![
+                Violation::test("no-unwrap", "a.rs", content, "unwrap()"),
+                Violation::test("no-overflow", "a.rs", content, "checked_add"),
+            ],
+            suppressions: SuppressionReport::default(),
+            budget_exceeded: None,
+            not_evaluated: 0,
+            failed: 0,
+        };
+        // The check that found nothing this time drops its violation, the one that ran out of
+        // budget keeps it.
+        let outcome = LintOutcome {
+            budget_exceeded: Some("1 requests sent, limit is 1".to_string()),
+            not_evaluated: vec![&no_unwrap],
+            evaluated: vec![&no_overflow],
+            ..Default::default()
+        };
+        let checked = Checked::new(outcome, SuppressionReport::default(), Some(&previous));
+        let rule_ids: Vec<_> = checked
+            .violations
+            .iter()
+            .map(|v| v.rule_id.as_str())
+            .collect();
+        assert_eq!(rule_ids, ["no-unwrap"]);
+        assert_eq!(checked.not_evaluated, 1);
+
+        let outcome = LintOutcome {
+            violations: previous.violations[..1].to_vec(),
+            failed: vec![&no_unwrap, &no_overflow],
+            ..Default::default()
+        };
+        let checked = Checked::new(outcome, SuppressionReport::default(), Some(&previous));
+        assert_eq!(checked.violations, previous.violations);
+        assert_eq!(checked.failed, 2);
+    }
+}
@@ -1 +1,2 @@
-pub mod project_rules;
\ No newline at end of file
+pub mod project_rules;
+pub mod rule_config;
@@ -1 +1,191 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
 
+use crate::{
+    parser::context::ContextSources,
+    rules::{generic::Rule, scope::ProjectRule},
+};
+
+pub const PROJECT_RULES_FILE: &str = ".melangerules";
+
+static RULE_LINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?:AIRULE(?:\(([^)\s]+)\))?:)?\s*(.+)$").unwrap());
+
+/// A rule disabled by a `!<rule id>` line.
+#[derive(Debug, Clone)]
+pub struct DisabledRule {
+    pub id: String,
+    pub source: String,
+}
+
+/// The project rules in effect for one file.
+#[derive(Debug, Default)]
+pub struct EffectiveRules {
+    pub rules: Vec<ProjectRule>,
+    pub disabled: Vec<DisabledRule>,
+}
+
+impl EffectiveRules {
+    fn disable(&mut self, disabled: DisabledRule) {
+        self.rules.retain(|rule| rule.rule.id() != disabled.id);
+        self.disabled.push(disabled);
+    }
+
+    /// Adds `rule`, in place of the rule with the same id if there is one.
+    fn add(&mut self, rule: ProjectRule) {
+        self.disabled
+            .retain(|disabled| disabled.id != rule.rule.id());
+        match self
+            .rules
+            .iter_mut()
+            .find(|r| r.rule.id() == rule.rule.id())
+        {
+            Some(existing) => *existing = rule,
+            None => self.rules.push(rule),
+        }
+    }
+}
+
+/// The rules of a `.melangerules` file, one per line. Blank lines and `#` comments are skipped,
+/// the `AIRULE:` prefix is optional, `AIRULE(<id>): <rule>` gives the rule an explicit id and
+/// `!<rule id>` disables a rule of an outer directory.
+fn parse_rules_file(path: &Path, content: &str, rules: &mut EffectiveRules) {
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let source = format!("{}:{}", path.display(), i + 1);
+        if let Some(id) = line.strip_prefix('!') {
+            rules.disable(DisabledRule {
+                id: id.trim().to_string(),
+                source,
+            });
+        } else if let Some(caps) = RULE_LINE.captures(line) {
+            let rule = match caps.get(1) {
+                Some(id) => Rule::with_id(id.as_str(), caps[2].trim()),
+                None => Rule::new(caps[2].trim()),
+            };
+            rules.add(ProjectRule::new(rule, source));
+        }
+    }
+}
+
+/// The directories whose `.melangerules` apply to `file`, from the working directory down.
+pub(crate) fn rule_dirs(file: &str) -> Vec<PathBuf> {
+    let path = Path::new(file);
+    let cwd = env::current_dir().unwrap_or_default();
+    let relative = path
+        .strip_prefix(&cwd)
+        .or_else(|_| path.strip_prefix("."))
+        .unwrap_or(path);
+    let mut dirs = vec![PathBuf::new()];
+    if relative.is_relative() {
+        let mut dir = PathBuf::new();
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            dirs.push(dir.clone());
+        }
+    }
+    dirs
+}
+
+/// The project rules: those of the config, then those of the `.melangerules` files of every
+/// directory from the working directory down to the checked file. Inner files add rules,
+/// replace the outer rules with the same id, and disable rules by id.
+#[derive(Debug, Default)]
+pub struct ProjectRules {
+    configured: Vec<ProjectRule>,
+    sources: ContextSources,
+    // Ids of the rules with prompt templates of their own, which are never batched.
+    own_templates: HashSet<String>,
+}
+
+impl ProjectRules {
+    pub fn new(configured: Vec<ProjectRule>) -> Self {
+        Self {
+            configured,
+            sources: ContextSources::default(),
+            own_templates: HashSet::new(),
+        }
+    }
+
+    /// Keeps the rules with the given ids out of batches, since a batch is rendered with a
+    /// single template.
+    pub fn with_own_templates(mut self, rule_ids: impl IntoIterator<Item = String>) -> Self {
+        self.own_templates = rule_ids.into_iter().collect();
+        self
+    }
+
+    /// Whether `rule` is rendered with a template of its own.
+    pub fn has_own_template(&self, rule: &Rule) -> bool {
+        self.own_templates.contains(rule.id())
+    }
+
+    /// The related files of the rules with `context`, read once for all the checked files.
+    pub fn context_sources(&self) -> &ContextSources {
+        &self.sources
+    }
+
+    /// The rules in effect for `file`. The rule files are read again on every call, so that
+    /// long-running commands pick up their changes.
+    pub fn for_file(&self, file: &str) -> Result<EffectiveRules> {
+        let mut rules = EffectiveRules::default();
+        for rule in &self.configured {
+            rules.add(rule.clone());
+        }
+        for dir in rule_dirs(file) {
+            let path = dir.join(PROJECT_RULES_FILE);
+            match fs::read_to_string(&path) {
+                Ok(content) => parse_rules_file(&path, &content, &mut rules),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+                }
+            }
+        }
+        Ok(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cascading_rules() {
+        let mut rules = EffectiveRules::default();
+        rules.add(ProjectRule::new(
+            Rule::with_id("docs", "items should be documented"),
+            "melange-config.toml".to_string(),
+        ));
+        parse_rules_file(
+            Path::new(".melangerules"),
+            "# outer\nNo blocking IO in async code.\nAIRULE(unsafe): unsafe blocks need a comment\n",
+            &mut rules,
+        );
+        parse_rules_file(
+            Path::new("crates/core/.melangerules"),
+            "!docs\nAIRULE(unsafe): unsafe blocks need a SAFETY comment\n",
+            &mut rules,
+        );
+        let ids: Vec<_> = rules.rules.iter().map(|rule| rule.rule.id()).collect();
+        assert_eq!(
+            ids,
+            vec![Rule::new("No blocking IO in async code.").id(), "unsafe"]
+        );
+        assert_eq!(rules.rules[1].source, "crates/core/.melangerules:2");
+        assert_eq!(rules.disabled[0].source, "crates/core/.melangerules:1");
+
+        let dirs = rule_dirs("./crates/core/src/lib.rs");
+        assert_eq!(dirs.len(), 4);
+        assert_eq!(dirs[3], Path::new("crates/core/src"));
+    }
+}
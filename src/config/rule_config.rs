@@ -0,0 +1,92 @@
+use serde::Deserialize;
+
+use crate::rules::{
+    generic::{Rule, Severity},
+    scope::{ProjectRule, RuleScope},
+};
+
+/// A rule declared in the config file, under `[[rules]]`, with the files and items it applies
+/// to, and examples of code that should and should not comply with it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleConfig {
+    pub id: Option<String>,
+    pub description: String,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(flatten)]
+    pub scope: RuleScope,
+    /// Globs of the related files the rule refers to, such as tests in another directory.
+    #[serde(default)]
+    pub context: Vec<String>,
+    /// Lets the model look up code across the crate with tool calls before giving its verdict.
+    #[serde(default)]
+    pub tools: bool,
+    #[serde(default)]
+    pub should_pass: Vec<String>,
+    #[serde(default)]
+    pub should_fail: Vec<String>,
+}
+
+impl RuleConfig {
+    pub fn rule(&self) -> Rule {
+        let rule = match &self.id {
+            Some(id) => Rule::with_id(id, &self.description),
+            None => Rule::new(&self.description),
+        };
+        rule.with_severity(self.severity)
+    }
+
+    /// The rule as a project rule, declared as the `index`th `[[rules]]` entry, from 0, of the
+    /// config at `config_path`.
+    pub fn project_rule(&self, config_path: &str, index: usize) -> ProjectRule {
+        ProjectRule {
+            rule: self.rule(),
+            scope: self.scope.clone(),
+            source: format!("{}, [[rules]] #{}", config_path, index + 1),
+            context: self.context.clone(),
+            tools: self.tools,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Config {
+        rules: Vec<RuleConfig>,
+    }
+
+    #[test]
+    fn test_rule_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[rules]]
+            id = "no-unwrap"
+            description = "library code should not call unwrap"
+            should_pass = ["fn a() -> Option<i32> { Some(1) }"]
+            should_fail = ["fn a() -> i32 { Some(1).unwrap() }"]
+
+            [[rules]]
+            description = "functions should have a doc comment"
+            severity = "info"
+            paths = ["src/**"]
+            item_kinds = ["fn"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rules[0].rule().id(), "no-unwrap");
+        assert_eq!(config.rules[0].should_fail.len(), 1);
+        assert_eq!(
+            config.rules[1].rule(),
+            Rule::new("functions should have a doc comment").with_severity(Severity::Info)
+        );
+        assert!(config.rules[0].scope.is_file_level());
+        assert!(!config.rules[1].scope.is_file_level());
+        assert_eq!(
+            config.rules[1].project_rule("ci.toml", 1).source,
+            "ci.toml, [[rules]] #2"
+        );
+    }
+}
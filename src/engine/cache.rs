@@ -0,0 +1,121 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, io::ErrorKind, path::Path};
+
+/// Where the CLI keeps its result cache between runs.
+pub const CACHE_FILE: &str = ".melange/cache.json";
+
+/// FNV-1a hash of `parts`, stable across runs and platforms.
+pub fn stable_hash(parts: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.bytes().chain(std::iter::once(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+/// Runs after which a response that was not asked for again is dropped from the cache, so that
+/// the responses of code that changed do not pile up, while runs over part of the files keep the
+/// responses of the others.
+const MAX_IDLE_RUNS: u64 = 10;
+
+/// Model responses keyed by the hash of the model, the system prompt and the prompt. Responses
+/// are parsed again on every hit, so that violations follow their item around the file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ResultCache {
+    responses: HashMap<String, String>,
+    // Number of the current run, counting the loads of the cache.
+    #[serde(default)]
+    run: u64,
+    // The last run that used each response.
+    #[serde(default)]
+    last_used: HashMap<String, u64>,
+}
+
+impl ResultCache {
+    /// Loads the cache at `path` for a new run.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let mut cache: Self = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        cache.run += 1;
+        Ok(cache)
+    }
+
+    /// Saves the cache to `path`, without the responses no run used for a while.
+    pub fn save(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let run = self.run;
+        let last_used = &self.last_used;
+        self.responses.retain(|key, _| {
+            last_used
+                .get(key)
+                .is_some_and(|&used| run - used < MAX_IDLE_RUNS)
+        });
+        let responses = &self.responses;
+        self.last_used.retain(|key, _| responses.contains_key(key));
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&String> {
+        let response = self.responses.get(key)?;
+        self.last_used.insert(key.to_string(), self.run);
+        Some(response)
+    }
+
+    pub fn insert(&mut self, key: String, response: String) {
+        self.last_used.insert(key.clone(), self.run);
+        self.responses.insert(key, response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixture;
+
+    #[test]
+    fn test_stable_hash() {
+        // The offset basis of FNV-1a, and the hash of "a" and its separator.
+        assert_eq!(stable_hash(&[]), "cbf29ce484222325");
+        assert_eq!(stable_hash(&["a"]), stable_hash(&["a"]));
+        assert_eq!(stable_hash(&["a"]), "089be207b544f1e4");
+        // Parts are separated, so moving text from one to the next changes the hash.
+        assert_ne!(stable_hash(&["ab", "c"]), stable_hash(&["a", "bc"]));
+        assert_ne!(stable_hash(&["a", ""]), stable_hash(&["a"]));
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        let fixture = Fixture::new(&[]);
+        let path = fixture.path().join(".melange/cache.json");
+        let mut cache = ResultCache::load(&path).unwrap();
+        assert!(cache.get("a").is_none());
+        cache.insert("a".to_string(), r#"{"violations": []}"#.to_string());
+        cache.insert("b".to_string(), "{}".to_string());
+        cache.save(&path).unwrap();
+
+        let mut cache = ResultCache::load(&path).unwrap();
+        assert_eq!(cache.get("a").unwrap(), r#"{"violations": []}"#);
+        cache.save(&path).unwrap();
+
+        // `b` goes once it was not used for long enough, while `a` is kept by every run.
+        for _ in 1..MAX_IDLE_RUNS {
+            let mut cache = ResultCache::load(&path).unwrap();
+            assert!(cache.get("a").is_some());
+            cache.save(&path).unwrap();
+        }
+        let mut cache = ResultCache::load(&path).unwrap();
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+    }
+}
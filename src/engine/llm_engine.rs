@@ -2,13 +2,37 @@ use anyhow::Result;
 use llm::{
     LLMProvider,
     builder::{LLMBackend, LLMBuilder},
-    chat::{ChatMessage, ChatRole, MessageType},
+    chat::{ChatMessage, ChatResponse, ChatRole, MessageType, Tool},
     secret_store::SecretStore,
 };
+use log::debug;
 use serde::Deserialize;
-use std::{fs, str::FromStr};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use crate::rules::generic::RuleWithCode;
+use crate::{
+    config::rule_config::RuleConfig,
+    engine::{
+        cache::{ResultCache, stable_hash},
+        pricing::{ModelPrice, find_price},
+        prompt::{PLACEHOLDER_NONCE, PromptTemplates, TemplateConfig, escape, nonce},
+        redact::{RedactionConfig, Redactor, drop_redacted_fixes},
+        tokens::{DEFAULT_CONTEXT_LIMIT, EXPECTED_RESPONSE_TOKENS, estimate_tokens},
+        tools::{CrateIndex, DEFAULT_MAX_TOOL_STEPS, TOOLS_PROMPT},
+        usage::{Usage, UsageReport},
+    },
+    errors::melange_errors::MelangeError,
+    rules::{
+        generic::RuleWithCode,
+        violation::{Violation, merge_violations, parse_response},
+    },
+};
 
 #[derive(Deserialize)]
 pub struct LlmConfig {
@@ -16,10 +40,41 @@ pub struct LlmConfig {
     pub model: Option<String>,
     pub temperature: Option<f32>,
     pub max_tokens: Option<usize>,
+    /// Size of the model's context window, in tokens.
+    pub context_limit: Option<usize>,
+    #[serde(default)]
+    pub pricing: Vec<ModelPrice>,
+    /// Requests stop being sent once the run has cost this much.
+    pub max_cost_usd: Option<f64>,
+    /// Requests stop being sent once this many have been sent.
+    pub max_requests: Option<usize>,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// Replaces the default system prompt, e.g. to compare prompts with `melange eval`.
+    pub system_prompt: Option<String>,
+    /// Templates of the system prompt and of the prompt of every rule check.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+    #[serde(skip)]
+    prompt_templates: PromptTemplates,
+    /// Most rounds of tool calls the model can make while checking a rule with `tools`.
+    pub max_tool_steps: Option<usize>,
+    /// How secrets are found in the code, to redact them before it is sent.
+    #[serde(default)]
+    pub redaction: RedactionConfig,
+    #[serde(skip)]
+    redactor: Redactor,
 }
 
 pub struct LlmEngine {
-    provider: Box<dyn LLMProvider>,
+    // One provider per system prompt, since the `llm` crate sets it when building a provider.
+    providers: Mutex<HashMap<String, Arc<dyn LLMProvider>>>,
+    config: LlmConfig,
+    usage: Mutex<UsageReport>,
+    // Only engines given a cache reuse responses; the others query the model every time.
+    cache: Mutex<Option<ResultCache>>,
+    // The crate explored by the rules with tools, indexed on the first of them.
+    index: Mutex<Option<Arc<CrateIndex>>>,
 }
 
 fn get_api_key(backend: &LLMBackend) -> Option<String> {
@@ -53,82 +108,462 @@ fn get_api_key(backend: &LLMBackend) -> Option<String> {
         LLMBackend::Phind => None,
     }
 }
-struct SystemPrompt {
-    prompt: String,
-}
-impl From<SystemPrompt> for String {
-    fn from(val: SystemPrompt) -> Self {
-        val.prompt
+
+/// Language of the code melange parses, for the templates of the prompts sent outside of
+/// rule checks.
+const RUST: &str = "rust";
+
+fn build_provider(config: &LlmConfig, system_prompt: &str) -> Result<Arc<dyn LLMProvider>> {
+    let backend = LLMBackend::from_str(&config.provider)
+        .map_err(|e| anyhow::anyhow!("Invalid provider: {}", e))?;
+    let api_key = get_api_key(&backend);
+    let mut builder = LLMBuilder::new()
+        .backend(backend)
+        .system(system_prompt)
+        .stream(false);
+
+    if let Some(api_key) = api_key {
+        builder = builder.api_key(api_key);
     }
+    if let Some(model) = &config.model {
+        builder = builder.model(model);
+    }
+    if let Some(max_tokens) = config.max_tokens {
+        builder = builder.max_tokens(max_tokens as u32);
+    }
+
+    if let Some(temp) = config.temperature {
+        builder = builder.temperature(temp);
+    }
+
+    let provider = builder
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to build provider: {}", e))?;
+    Ok(Arc::from(provider))
 }
 
-impl Default for SystemPrompt {
-    fn default() -> Self {
-        Self {
-            prompt: r#"
-            Check the provided rule, surrounded by <rule> tags, against the subsequent piece of code,
-            surrounded by <code> tags. 
-            Make sure you are as fastidious as possible. 
-            Quote the beginning of every potential violation.
-            Include the specific way in which the code instance violates the rule.
-            Be as brief as possible.
-            The response should be one valid json object, containing a list of tuples where each tuple 
-            is a pair of rule with its corresponding violation.
-            "#
-            .to_string(),
+impl LlmConfig {
+    pub fn from_file(config_path: &str) -> Result<Self> {
+        let config_content = fs::read_to_string(config_path)?;
+        let mut config: Self = toml::from_str(&config_content)?;
+        if let Some(dir) = Path::new(config_path).parent() {
+            config.templates.resolve_files(dir);
+        }
+        config.prompt_templates =
+            PromptTemplates::new(&config.templates, config.system_prompt.as_deref())?;
+        // Surfaces the errors of the system template now rather than on the first request.
+        config.prompt_templates.system(RUST)?;
+        config.redactor = Redactor::new(&config.redaction, &config.provider)?;
+        Ok(config)
+    }
+
+    /// The system prompt for Rust code, the language melange checks.
+    pub fn system_prompt(&self) -> String {
+        self.prompt_templates
+            .system(RUST)
+            .expect("the system template rendered when the config was loaded")
+    }
+
+    /// Swaps the secrets in the code of rule checks, and in tool results, for placeholders.
+    pub fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    /// The system prompt for the check of `rule`, from the most specific template.
+    pub fn system_prompt_for(&self, rule: &RuleWithCode) -> Result<String> {
+        self.prompt_templates.system_for(rule)
+    }
+
+    pub fn estimate_tokens(&self, text: &str) -> usize {
+        estimate_tokens(self.model.as_deref().unwrap_or_default(), text)
+    }
+
+    /// Tokens left for the user prompt once the system prompt and the response are accounted for.
+    pub fn prompt_budget(&self) -> usize {
+        self.context_limit
+            .unwrap_or(DEFAULT_CONTEXT_LIMIT)
+            .saturating_sub(self.max_tokens.unwrap_or(0))
+            .saturating_sub(self.estimate_tokens(&self.system_prompt()))
+    }
+
+    /// Expected number of output tokens of a single request.
+    pub fn expected_response_tokens(&self) -> usize {
+        self.max_tokens.map_or(EXPECTED_RESPONSE_TOKENS, |max| {
+            max.min(EXPECTED_RESPONSE_TOKENS)
+        })
+    }
+
+    fn price(&self) -> Option<&ModelPrice> {
+        find_price(&self.pricing, &self.provider, self.model.as_deref())
+    }
+
+    /// The prompt `rule` is sent as, from the most specific template with its sections
+    /// delimited by `nonce`, and with the instructions for the tools when it uses them.
+    pub fn prompt(&self, rule: &RuleWithCode, nonce: &str) -> Result<String> {
+        let prompt = self.prompt_templates.prompt(rule, nonce)?;
+        if rule.uses_tools() {
+            Ok(format!("{}{}", TOOLS_PROMPT, prompt))
+        } else {
+            Ok(prompt)
         }
     }
+
+    /// The prompts `rule` is sent as: the item itself, or its chunks when it does not fit in the
+    /// model's context.
+    pub fn chunks(&self, rule: &RuleWithCode) -> Vec<RuleWithCode> {
+        // Prompts that fail to render are left whole, and the error shows when they are sent.
+        rule.split(self.prompt_budget(), |chunk| {
+            self.prompt(chunk, PLACEHOLDER_NONCE)
+                .map_or(0, |prompt| self.estimate_tokens(&prompt))
+        })
+    }
 }
 
 impl LlmEngine {
     pub fn from_config(config_path: &str) -> Result<Self> {
-        let config_content = fs::read_to_string(config_path)?;
-        let config: LlmConfig = toml::from_str(&config_content)?;
-        let backend = LLMBackend::from_str(&config.provider)
-            .map_err(|e| anyhow::anyhow!("Invalid provider: {}", e))?;
-        let api_key = get_api_key(&backend);
-        let system_prompt = SystemPrompt::default();
-        let mut builder = LLMBuilder::new()
-            .backend(backend)
-            .system(system_prompt)
-            .stream(false);
-
-        if let Some(api_key) = api_key {
-            builder = builder.api_key(api_key);
+        Self::new(LlmConfig::from_file(config_path)?)
+    }
+
+    /// An engine sending every request through `provider`.
+    #[cfg(test)]
+    pub(crate) fn with_provider(config: LlmConfig, provider: Arc<dyn LLMProvider>) -> Self {
+        Self {
+            providers: Mutex::new(HashMap::from([(config.system_prompt(), provider)])),
+            config,
+            usage: Mutex::new(UsageReport::default()),
+            cache: Mutex::new(None),
+            index: Mutex::new(None),
         }
-        if let Some(model) = config.model {
-            builder = builder.model(&model);
+    }
+
+    pub fn new(config: LlmConfig) -> Result<Self> {
+        let system_prompt = config.system_prompt();
+        let provider = build_provider(&config, &system_prompt)?;
+        Ok(Self {
+            providers: Mutex::new(HashMap::from([(system_prompt, provider)])),
+            config,
+            usage: Mutex::new(UsageReport::default()),
+            cache: Mutex::new(None),
+            index: Mutex::new(None),
+        })
+    }
+
+    /// The provider sending requests with `system_prompt`.
+    fn provider(&self, system_prompt: &str) -> Result<Arc<dyn LLMProvider>> {
+        let mut providers = self.providers.lock().unwrap();
+        if let Some(provider) = providers.get(system_prompt) {
+            return Ok(Arc::clone(provider));
         }
+        let provider = build_provider(&self.config, system_prompt)?;
+        providers.insert(system_prompt.to_string(), Arc::clone(&provider));
+        Ok(provider)
+    }
 
-        if let Some(temp) = config.temperature {
-            builder = builder.temperature(temp);
+    /// Reuses the responses of `cache` for the prompts it already holds.
+    pub fn with_cache(self, cache: ResultCache) -> Self {
+        Self {
+            cache: Mutex::new(Some(cache)),
+            ..self
         }
+    }
 
-        let provider = builder
-            .build()
-            .map_err(|e| anyhow::anyhow!("Failed to build provider: {}", e))?;
-        Ok(Self { provider })
+    pub fn save_cache(&self, path: &str) -> Result<()> {
+        match self.cache.lock().unwrap().as_mut() {
+            Some(cache) => cache.save(path),
+            None => Ok(()),
+        }
     }
 
-    pub async fn query_with_rule(&self, rule: RuleWithCode) -> Result<String> {
-        self.query(rule.to_prompt().as_str()).await
+    /// Usage of all the rule checks run so far.
+    pub fn usage_report(&self) -> UsageReport {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Fails with [`MelangeError::BudgetExceeded`] when sending `prompt` could take the run over
+    /// its request or cost limits.
+    fn check_budget(&self, system_prompt: &str, prompt: &str) -> Result<(), MelangeError> {
+        let spent = self.usage.lock().unwrap().total.clone();
+        if let Some(max_requests) = self.config.max_requests
+            && spent.requests >= max_requests
+        {
+            return Err(MelangeError::budget_exceeded(format!(
+                "{} requests sent, limit is {}",
+                spent.requests, max_requests
+            )));
+        }
+        if let Some(max_cost_usd) = self.config.max_cost_usd {
+            let input_tokens =
+                self.config.estimate_tokens(system_prompt) + self.config.estimate_tokens(prompt);
+            let next_cost = self.config.price().map_or(0.0, |price| {
+                price.cost(input_tokens, self.config.expected_response_tokens())
+            });
+            if spent.cost_usd + next_cost > max_cost_usd {
+                return Err(MelangeError::budget_exceeded(format!(
+                    "${:.4} spent, next request would go over the ${:.4} limit",
+                    spent.cost_usd, max_cost_usd
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The index of the crate in the working directory, built on first use.
+    fn crate_index(&self) -> Result<Arc<CrateIndex>> {
+        let mut index = self.index.lock().unwrap();
+        if index.is_none() {
+            *index = Some(Arc::new(CrateIndex::new(".")?));
+        }
+        Ok(Arc::clone(index.as_ref().unwrap()))
+    }
+
+    /// Checks `rule` against its code, splitting the item into several prompts when it does not
+    /// fit in the model's context. When the budget runs out partway, the error carries the
+    /// violations found in the chunks already checked.
+    pub async fn query_with_rule(&self, rule: &RuleWithCode) -> Result<Vec<Violation>> {
+        let chunks = self.config.chunks(rule);
+        if chunks.len() > 1 {
+            debug!("Split {} into {} chunks", rule.file_name(), chunks.len());
+        }
+        let index = if rule.uses_tools() {
+            Some(self.crate_index()?)
+        } else {
+            None
+        };
+        let mut violations = Vec::new();
+        for chunk in chunks {
+            match self.query_chunk(&chunk, index.as_deref()).await {
+                Ok(found) => violations.push(found),
+                Err(e) => {
+                    return Err(match e.downcast::<MelangeError>() {
+                        Ok(MelangeError::BudgetExceeded { reason, .. }) => {
+                            MelangeError::BudgetExceeded {
+                                reason,
+                                found: merge_violations(violations),
+                            }
+                            .into()
+                        }
+                        Err(e) => e,
+                    });
+                }
+            }
+        }
+        Ok(merge_violations(violations))
+    }
+
+    /// Checks one chunk of a rule check, from the cache when it was answered before.
+    async fn query_chunk(
+        &self,
+        original: &RuleWithCode,
+        index: Option<&CrateIndex>,
+    ) -> Result<Vec<Violation>> {
+        // Violations are found in the redacted code, whose offsets are those of the original.
+        let chunk = self.config.redactor.redact(original);
+        let system_prompt = self.config.system_prompt_for(&chunk)?;
+        let nonce = nonce(&chunk);
+        let prompt = self.config.prompt(&chunk, &nonce)?;
+        // The nonce changes with every request, and is left out of the key of the cache.
+        let key = stable_hash(&[
+            &self.config.provider,
+            self.config.model.as_deref().unwrap_or_default(),
+            &system_prompt,
+            &prompt.replace(&nonce, ""),
+        ]);
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|cache| cache.get(&key).cloned());
+        if let Some(response) = cached {
+            debug!("Cache hit for {}", chunk.item_name());
+            return Ok(drop_redacted_fixes(
+                parse_response(&response, &chunk)?,
+                original,
+                &chunk,
+            ));
+        }
+        let response = match index {
+            Some(index) => {
+                self.query_with_tools(&chunk, &system_prompt, &prompt, &nonce, index)
+                    .await?
+            }
+            None => {
+                self.check_budget(&system_prompt, &prompt)?;
+                let (response, usage) = self.send(&system_prompt, &prompt).await?;
+                self.usage
+                    .lock()
+                    .unwrap()
+                    .record(chunk.file_name(), chunk.rules(), &usage);
+                response
+            }
+        };
+        let violations = drop_redacted_fixes(parse_response(&response, &chunk)?, original, &chunk);
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.insert(key, response);
+        }
+        Ok(violations)
     }
 
     pub async fn query(&self, prompt: &str) -> Result<String> {
-        let messages = vec![ChatMessage {
-            role: ChatRole::User,
-            message_type: MessageType::Text,
-            content: prompt.to_string(),
-        }];
-        let response = self.provider.chat(&messages).await?;
-        response
+        Ok(self.query_with_usage(prompt).await?.0)
+    }
+
+    /// Sends `prompt`, with its secrets redacted, and returns the response along with the
+    /// resources it took.
+    pub async fn query_with_usage(&self, prompt: &str) -> Result<(String, Usage)> {
+        let prompt = self.config.redactor.redact_text(prompt);
+        self.send(&self.config.system_prompt(), &prompt).await
+    }
+
+    async fn send(&self, system_prompt: &str, prompt: &str) -> Result<(String, Usage)> {
+        let (response, usage) = self
+            .chat(system_prompt, &[user_message(prompt)], None)
+            .await?;
+        let text = response
             .text()
-            .ok_or(anyhow::anyhow!("Failed to get response text"))
+            .ok_or(anyhow::anyhow!("Failed to get response text"))?;
+        Ok((text, usage))
+    }
+
+    /// Sends `prompt` along with the tools over `index`, and runs the tool calls of the model
+    /// until it gives its verdict. Once `max_tool_steps` rounds of calls are spent, the tools
+    /// are no longer offered. Every round is a request of its own, recorded against `chunk` and
+    /// counted against the budget as soon as it is answered.
+    /// Results are framed with the `nonce` of the prompt, which is escaped inside them.
+    async fn query_with_tools(
+        &self,
+        chunk: &RuleWithCode,
+        system_prompt: &str,
+        prompt: &str,
+        nonce: &str,
+        index: &CrateIndex,
+    ) -> Result<String> {
+        let tools = CrateIndex::tools();
+        let max_steps = self.config.max_tool_steps.unwrap_or(DEFAULT_MAX_TOOL_STEPS);
+        let mut messages = vec![user_message(prompt)];
+        for step in 0.. {
+            let conversation: String = messages.iter().map(|m| m.content.as_str()).collect();
+            self.check_budget(system_prompt, &conversation)?;
+            let offered = (step < max_steps).then_some(tools.as_slice());
+            let (response, usage) = self.chat(system_prompt, &messages, offered).await?;
+            self.usage
+                .lock()
+                .unwrap()
+                .record(chunk.file_name(), chunk.rules(), &usage);
+            let calls = response.tool_calls().unwrap_or_default();
+            if calls.is_empty() || offered.is_none() {
+                let text = response
+                    .text()
+                    .ok_or(anyhow::anyhow!("Failed to get response text"))?;
+                return Ok(text);
+            }
+            debug!(
+                "Tool calls: {}",
+                calls
+                    .iter()
+                    .map(|call| format!("{}({})", call.function.name, call.function.arguments))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            // The chat messages of the llm crate have no tool roles, so the calls and their
+            // results go back to the model as text.
+            messages.push(ChatMessage {
+                role: ChatRole::Assistant,
+                message_type: MessageType::Text,
+                content: serde_json::to_string(&calls)?,
+            });
+            let mut results: String = calls
+                .iter()
+                .map(|call| {
+                    format!(
+                        "<tool_result-{nonce}>\n<call-{nonce}>{}</call-{nonce}>\n{}\n</tool_result-{nonce}>\n",
+                        escape(&call.id, nonce),
+                        escape(&self.config.redactor.redact_text(&index.call(call)), nonce)
+                    )
+                })
+                .collect();
+            if step + 1 == max_steps {
+                results.push_str("No more tools can be called, give your verdict now.");
+            }
+            messages.push(user_message(&results));
+        }
+        unreachable!()
+    }
+
+    /// Sends `messages` after `system_prompt`, offering `tools` if any, and measures the
+    /// resources it took.
+    async fn chat(
+        &self,
+        system_prompt: &str,
+        messages: &[ChatMessage],
+        tools: Option<&[Tool]>,
+    ) -> Result<(Box<dyn ChatResponse>, Usage)> {
+        let provider = self.provider(system_prompt)?;
+        let start = Instant::now();
+        let response = provider.chat_with_tools(messages, tools).await?;
+        let latency_ms = start.elapsed().as_millis() as u64;
+
+        let input_tokens = self.config.estimate_tokens(system_prompt)
+            + messages
+                .iter()
+                .map(|message| self.config.estimate_tokens(&message.content))
+                .sum::<usize>();
+        let output_tokens = self
+            .config
+            .estimate_tokens(&response.text().unwrap_or_default());
+        let cost_usd = self
+            .config
+            .price()
+            .map_or(0.0, |price| price.cost(input_tokens, output_tokens));
+        let usage = Usage {
+            requests: 1,
+            input_tokens,
+            output_tokens,
+            latency_ms,
+            cost_usd,
+        };
+        Ok((response, usage))
+    }
+}
+
+fn user_message(content: &str) -> ChatMessage {
+    ChatMessage {
+        role: ChatRole::User,
+        message_type: MessageType::Text,
+        content: content.to_string(),
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        engine::mock::{self, MockResponse},
+        fixture::Fixture,
+        rules::generic::Rule,
+    };
+
+    #[test]
+    fn test_template_files() {
+        let fixture = Fixture::new(&[
+            (
+                "melange-config.toml",
+                b"provider = \"ollama\"\n[templates.rules.no-unwrap]\nprompt_file = \"templates/prompt.j2\"\n",
+            ),
+            ("templates/prompt.j2", b"{{ rule.description }}: {{ code }}"),
+        ]);
+        // The template file is found next to the config, wherever melange runs.
+        let config_path = fixture.path().join("melange-config.toml");
+        let config = LlmConfig::from_file(config_path.to_str().unwrap()).unwrap();
+        let rule = RuleWithCode::snippet(
+            Rule::with_id("no-unwrap", "no unwrap"),
+            "first".to_string(),
+            "x.unwrap()",
+        );
+        assert_eq!(
+            config.prompt(&rule, PLACEHOLDER_NONCE).unwrap(),
+            "no unwrap: x.unwrap()"
+        );
+    }
 
     #[tokio::test]
     async fn test_with_config() {
@@ -139,4 +574,112 @@ mod tests {
             .unwrap();
         assert!(!response.is_empty());
     }
+
+    fn budget_error(e: anyhow::Error) -> (String, Vec<Violation>) {
+        match e.downcast::<MelangeError>() {
+            Ok(MelangeError::BudgetExceeded { reason, found }) => (reason, found),
+            Err(e) => panic!("not a budget error: {:#}", e),
+        }
+    }
+
+    fn snippet(code: &str) -> RuleWithCode {
+        RuleWithCode::snippet(Rule::new("no unwrap"), "f".to_string(), code)
+    }
+
+    #[test]
+    fn test_cost_cap() {
+        let engine = mock::engine(
+            mock::config(
+                r#"
+                model = "mock"
+                max_cost_usd = 1.0
+                [[pricing]]
+                provider = "ollama"
+                model = "mock"
+                input_per_mtok = 0.0
+                output_per_mtok = 1000.0
+                "#,
+            ),
+            &[],
+        );
+        // The next request is expected to cost 256 output tokens, that is $0.256.
+        let spent = |cost_usd| Usage {
+            requests: 1,
+            cost_usd,
+            ..Default::default()
+        };
+        engine
+            .usage
+            .lock()
+            .unwrap()
+            .record("a.rs", &[], &spent(0.7));
+        assert!(engine.check_budget("", "fn f() {}").is_ok());
+        engine
+            .usage
+            .lock()
+            .unwrap()
+            .record("a.rs", &[], &spent(0.1));
+        let e = engine.check_budget("", "fn f() {}").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Budget exceeded: $0.8000 spent, next request would go over the $1.0000 limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_cap() {
+        let engine = mock::engine(
+            mock::config("max_requests = 2"),
+            &[MockResponse::Text(r#"{"violations": []}"#.to_string())],
+        );
+        let rule = snippet("fn f() {}");
+        assert!(engine.query_with_rule(&rule).await.unwrap().is_empty());
+        assert!(engine.query_with_rule(&rule).await.unwrap().is_empty());
+        let (reason, found) = budget_error(engine.query_with_rule(&rule).await.unwrap_err());
+        assert_eq!(reason, "2 requests sent, limit is 2");
+        assert!(found.is_empty());
+        assert_eq!(engine.usage_report().total.requests, 2);
+    }
+
+    #[tokio::test]
+    async fn test_budget_keeps_checked_chunks() {
+        let body = "    let x = 0;\n".repeat(40);
+        let code = format!("fn a() {{\n{body}}}\nfn b() {{\n{body}}}\n");
+        let second = code.find("fn b").unwrap();
+        let rule = snippet(&code).with_sub_ranges(vec![0..second - 1, second..code.len() - 1]);
+        let mut config = mock::config("max_requests = 1");
+        // Room for the prompt of either function, but not both.
+        let whole = config.estimate_tokens(&config.prompt(&rule, PLACEHOLDER_NONCE).unwrap());
+        let system = config.estimate_tokens(&config.system_prompt());
+        config.context_limit = Some(system + whole - 50);
+        assert_eq!(config.chunks(&rule).len(), 2);
+
+        let engine = mock::engine(
+            config,
+            &[MockResponse::Text(
+                r#"{"violations": [{"quote": "fn a()", "explanation": "no docs"}]}"#.to_string(),
+            )],
+        );
+        let (_, found) = budget_error(engine.query_with_rule(&rule).await.unwrap_err());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].line, 1);
+    }
+
+    #[tokio::test]
+    async fn test_tool_loop_budget() {
+        let engine = mock::engine(
+            mock::config("max_requests = 2\nmax_tool_steps = 8"),
+            &[MockResponse::ToolCall(
+                "lookup_symbol".to_string(),
+                r#"{"name": "CrateIndex"}"#.to_string(),
+            )],
+        );
+        let rule = snippet("fn f() {}").with_tools(true);
+        let (reason, _) = budget_error(engine.query_with_rule(&rule).await.unwrap_err());
+        assert_eq!(reason, "2 requests sent, limit is 2");
+        // Every round of tool calls counts as soon as it is answered.
+        let usage = engine.usage_report();
+        assert_eq!(usage.total.requests, 2);
+        assert_eq!(usage.by_file["example.rs"].requests, 2);
+    }
 }
@@ -0,0 +1,115 @@
+use llm::{
+    FunctionCall, LLMProvider, ToolCall, async_trait,
+    chat::{ChatMessage, ChatProvider, ChatResponse, Tool},
+    completion::{CompletionProvider, CompletionRequest, CompletionResponse},
+    embedding::EmbeddingProvider,
+    error::LLMError,
+};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use crate::engine::llm_engine::{LlmConfig, LlmEngine};
+
+/// A canned answer of the [`MockProvider`].
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+    Text(String),
+    ToolCall(String, String),
+    Error(String),
+}
+
+#[derive(Debug)]
+struct MockChatResponse(MockResponse);
+
+impl Display for MockChatResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl ChatResponse for MockChatResponse {
+    fn text(&self) -> Option<String> {
+        match &self.0 {
+            MockResponse::Text(text) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    fn tool_calls(&self) -> Option<Vec<ToolCall>> {
+        match &self.0 {
+            MockResponse::ToolCall(name, arguments) => Some(vec![ToolCall {
+                id: "call_1".to_string(),
+                call_type: "function".to_string(),
+                function: FunctionCall {
+                    name: name.clone(),
+                    arguments: arguments.clone(),
+                },
+            }]),
+            _ => None,
+        }
+    }
+}
+
+/// A provider answering with canned responses, in order, so that tests never reach a model.
+/// Once they run out, it keeps giving the last one.
+pub struct MockProvider {
+    responses: Mutex<VecDeque<MockResponse>>,
+}
+
+#[async_trait]
+impl ChatProvider for MockProvider {
+    async fn chat_with_tools(
+        &self,
+        _messages: &[ChatMessage],
+        _tools: Option<&[Tool]>,
+    ) -> Result<Box<dyn ChatResponse>, LLMError> {
+        let mut responses = self.responses.lock().unwrap();
+        let response = match responses.len() {
+            0 => return Err(LLMError::ProviderError("no canned response".to_string())),
+            1 => responses[0].clone(),
+            _ => responses.pop_front().unwrap(),
+        };
+        match response {
+            MockResponse::Error(e) => Err(LLMError::ProviderError(e)),
+            response => Ok(Box::new(MockChatResponse(response))),
+        }
+    }
+}
+
+#[async_trait]
+impl CompletionProvider for MockProvider {
+    async fn complete(&self, _req: &CompletionRequest) -> Result<CompletionResponse, LLMError> {
+        Err(LLMError::ProviderError(
+            "completion is not mocked".to_string(),
+        ))
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for MockProvider {
+    async fn embed(&self, _input: Vec<String>) -> Result<Vec<Vec<f32>>, LLMError> {
+        Err(LLMError::ProviderError(
+            "embedding is not mocked".to_string(),
+        ))
+    }
+}
+
+impl LLMProvider for MockProvider {}
+
+/// A config for an ollama model, with the top-level settings and tables of `toml`.
+pub fn config(toml: &str) -> LlmConfig {
+    toml::from_str(&format!("provider = \"ollama\"\n{}", toml)).unwrap()
+}
+
+/// An engine answering with `responses` in order.
+pub fn engine(config: LlmConfig, responses: &[MockResponse]) -> LlmEngine {
+    LlmEngine::with_provider(
+        config,
+        Arc::new(MockProvider {
+            responses: Mutex::new(responses.iter().cloned().collect()),
+        }),
+    )
+}
@@ -1 +1,10 @@
-pub mod llm_engine;
\ No newline at end of file
+pub mod cache;
+pub mod llm_engine;
+#[cfg(test)]
+pub mod mock;
+pub mod pricing;
+pub mod prompt;
+pub mod redact;
+pub mod tokens;
+pub mod tools;
+pub mod usage;
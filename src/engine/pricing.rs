@@ -0,0 +1,80 @@
+use serde::Deserialize;
+
+/// Price of a provider/model pair, from the `[[pricing]]` tables of the config.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelPrice {
+    pub provider: String,
+    pub model: String,
+    /// USD per million input tokens.
+    pub input_per_mtok: f64,
+    /// USD per million output tokens.
+    pub output_per_mtok: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, input_tokens: usize, output_tokens: usize) -> f64 {
+        (input_tokens as f64 * self.input_per_mtok + output_tokens as f64 * self.output_per_mtok)
+            / 1_000_000.0
+    }
+}
+
+/// Looks up the price of a provider/model pair. A price for model `*` covers the models of the
+/// provider that have no price of their own, and without a model, the first price listed for
+/// the provider is used.
+pub fn find_price<'a>(
+    pricing: &'a [ModelPrice],
+    provider: &str,
+    model: Option<&str>,
+) -> Option<&'a ModelPrice> {
+    let mut prices = pricing.iter().filter(|p| p.provider == provider);
+    match model {
+        None => prices.next(),
+        Some(model) => {
+            let mut wildcard = None;
+            for price in prices {
+                if price.model == model {
+                    return Some(price);
+                }
+                if price.model == "*" {
+                    wildcard = wildcard.or(Some(price));
+                }
+            }
+            wildcard
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(provider: &str, model: &str, input_per_mtok: f64) -> ModelPrice {
+        ModelPrice {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            input_per_mtok,
+            output_per_mtok: 4.0 * input_per_mtok,
+        }
+    }
+
+    #[test]
+    fn test_find_price() {
+        let pricing = [
+            price("openai", "*", 1.0),
+            price("google", "gemini-2.0-flash", 0.1),
+            price("openai", "gpt-4o", 2.5),
+        ];
+        let found = find_price(&pricing, "openai", Some("gpt-4o")).unwrap();
+        assert_eq!(found.model, "gpt-4o");
+        assert_eq!(found.cost(1_000_000, 500_000), 2.5 + 5.0);
+        let found = find_price(&pricing, "openai", Some("o3")).unwrap();
+        assert_eq!(found.model, "*");
+        assert_eq!(found.cost(2_000, 0), 0.002);
+        assert_eq!(
+            find_price(&pricing, "google", None).unwrap().input_per_mtok,
+            0.1
+        );
+        assert!(find_price(&pricing, "google", Some("gemini-2.5-pro")).is_none());
+        assert!(find_price(&pricing, "anthropic", None).is_none());
+    }
+}
@@ -0,0 +1,29 @@
+/// Context window assumed when the config does not set `context_limit`.
+pub const DEFAULT_CONTEXT_LIMIT: usize = 8192;
+
+/// Typical size of a violations response, used when estimating the cost of a run.
+pub const EXPECTED_RESPONSE_TOKENS: usize = 256;
+
+/// Average number of characters per token for the tokenizer of a model family.
+/// Source code tokenizes denser than prose, so these lean on the low side.
+fn chars_per_token(model: &str) -> f64 {
+    let model = model.to_lowercase();
+    if model.starts_with("gpt-4o") || model.starts_with("o1") || model.starts_with("o3") {
+        3.8
+    } else if model.starts_with("gpt") {
+        3.5
+    } else if model.contains("claude") {
+        3.2
+    } else if model.contains("gemini") {
+        3.6
+    } else if model.contains("qwen") || model.contains("deepseek") {
+        3.3
+    } else {
+        3.0
+    }
+}
+
+/// Estimates the number of tokens `text` takes up for the given model.
+pub fn estimate_tokens(model: &str, text: &str) -> usize {
+    (text.chars().count() as f64 / chars_per_token(model)).ceil() as usize
+}
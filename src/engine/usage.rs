@@ -0,0 +1,131 @@
+use serde::Serialize;
+use std::{collections::BTreeMap, ops::AddAssign};
+
+use crate::rules::generic::Rule;
+
+/// Resources spent on one or more requests. The `llm` crate does not expose the token counts
+/// reported by the providers, so these are estimated from the prompt and response text.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    pub requests: usize,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    pub latency_ms: u64,
+    pub cost_usd: f64,
+}
+
+impl Usage {
+    /// Splits the usage of a request evenly between `n` rules sent in the same prompt.
+    fn share(&self, n: usize) -> Usage {
+        Usage {
+            requests: self.requests,
+            input_tokens: self.input_tokens / n,
+            output_tokens: self.output_tokens / n,
+            latency_ms: self.latency_ms / n as u64,
+            cost_usd: self.cost_usd / n as f64,
+        }
+    }
+}
+
+impl AddAssign<&Usage> for Usage {
+    fn add_assign(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.latency_ms += other.latency_ms;
+        self.cost_usd += other.cost_usd;
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UsageReport {
+    pub total: Usage,
+    pub by_file: BTreeMap<String, Usage>,
+    pub by_rule: BTreeMap<String, Usage>,
+}
+
+impl UsageReport {
+    /// Records a request checking `rules` against code from `file`.
+    pub fn record(&mut self, file: &str, rules: &[Rule], usage: &Usage) {
+        self.total += usage;
+        *self.by_file.entry(file.to_string()).or_default() += usage;
+        let share = usage.share(rules.len().max(1));
+        for rule in rules {
+            *self.by_rule.entry(rule.to_string()).or_default() += &share;
+        }
+    }
+
+    pub fn print_table(&self) {
+        println!("Usage (token counts are estimates)");
+        println!(
+            "{:<48} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "", "requests", "tokens in", "tokens out", "latency", "cost"
+        );
+        for (rule, usage) in &self.by_rule {
+            print_row(&format!("rule: {}", rule), usage);
+        }
+        for (file, usage) in &self.by_file {
+            print_row(&format!("file: {}", file), usage);
+        }
+        print_row("total", &self.total);
+    }
+}
+
+fn print_row(label: &str, usage: &Usage) {
+    let label = if label.chars().count() > 48 {
+        format!("{}...", label.chars().take(45).collect::<String>())
+    } else {
+        label.to_string()
+    };
+    println!(
+        "{:<48} {:>8} {:>10} {:>10} {:>8}ms {:>10}",
+        label,
+        usage.requests,
+        usage.input_tokens,
+        usage.output_tokens,
+        usage.latency_ms,
+        format!("${:.4}", usage.cost_usd)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let usage = |input_tokens| Usage {
+            requests: 1,
+            input_tokens,
+            output_tokens: 10,
+            latency_ms: 100,
+            cost_usd: 0.5,
+        };
+        let documented = Rule::with_id("documented", "document functions");
+        let no_panics = Rule::with_id("no-panics", "no panics");
+        let mut report = UsageReport::default();
+        // A batch of two rules shares the usage of its request between them.
+        report.record(
+            "src/a.rs",
+            &[documented.clone(), no_panics.clone()],
+            &usage(400),
+        );
+        report.record("src/b.rs", std::slice::from_ref(&documented), &usage(100));
+
+        assert_eq!(report.total.requests, 2);
+        assert_eq!(report.total.input_tokens, 500);
+        assert_eq!(report.total.cost_usd, 1.0);
+        assert_eq!(report.by_file["src/a.rs"].input_tokens, 400);
+        assert_eq!(report.by_file["src/b.rs"].requests, 1);
+
+        let documented = &report.by_rule[&documented.to_string()];
+        assert_eq!(documented.requests, 2);
+        assert_eq!(documented.input_tokens, 300);
+        assert_eq!(documented.output_tokens, 15);
+        assert_eq!(documented.latency_ms, 150);
+        assert_eq!(documented.cost_usd, 0.75);
+        let no_panics = &report.by_rule[&no_panics.to_string()];
+        assert_eq!(no_panics.input_tokens, 200);
+        assert_eq!(no_panics.cost_usd, 0.25);
+    }
+}
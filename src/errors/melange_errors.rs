@@ -0,0 +1,40 @@
+use std::fmt::Display;
+
+use crate::rules::violation::Violation;
+
+/// Exit code of a run that stopped because it hit one of its budget limits.
+pub const EXIT_BUDGET_EXCEEDED: i32 = 3;
+
+/// Exit code of `melange test-rules` when a rule passes its examples too rarely.
+pub const EXIT_AMBIGUOUS_RULES: i32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MelangeError {
+    /// A request was not sent because it would go over `max_requests` or `max_cost_usd`. The
+    /// violations found by the requests already sent for the same rule check are kept.
+    BudgetExceeded {
+        reason: String,
+        found: Vec<Violation>,
+    },
+}
+
+impl MelangeError {
+    pub fn budget_exceeded(reason: String) -> Self {
+        MelangeError::BudgetExceeded {
+            reason,
+            found: Vec::new(),
+        }
+    }
+}
+
+impl Display for MelangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MelangeError::BudgetExceeded { reason, .. } => {
+                write!(f, "Budget exceeded: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MelangeError {}
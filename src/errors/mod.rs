@@ -1 +1 @@
-pub mod melange_errors;
\ No newline at end of file
+pub mod melange_errors;
@@ -0,0 +1,124 @@
+use anyhow::Result;
+use std::{collections::BTreeMap, fs};
+
+use crate::rules::violation::{Fix, Violation};
+
+#[derive(Debug, Default)]
+pub struct FixReport {
+    pub applied: Vec<Violation>,
+    /// Fixes overlapping a fix that comes earlier in the same file.
+    pub conflicting: Vec<Violation>,
+    /// Fixes identical to one that comes earlier, such as those of two rules on the same code,
+    /// which are applied once.
+    pub duplicates: Vec<Violation>,
+    /// Fixes that were undone because the file no longer parsed with them.
+    pub rolled_back: Vec<Violation>,
+}
+
+impl FixReport {
+    fn extend(&mut self, other: FixReport) {
+        self.applied.extend(other.applied);
+        self.conflicting.extend(other.conflicting);
+        self.duplicates.extend(other.duplicates);
+        self.rolled_back.extend(other.rolled_back);
+    }
+}
+
+/// Applies the fixes of `violations` to `content`, which must be the content the violations
+/// were found in. Among overlapping fixes, the one starting first wins. Fixes are applied from
+/// the end of the file backwards, and each one is undone if the file stops parsing.
+pub fn fix_content(content: &str, violations: Vec<Violation>) -> (String, FixReport) {
+    let mut report = FixReport::default();
+    let mut fixes: Vec<(Fix, Violation)> = violations
+        .into_iter()
+        .filter_map(|v| v.fix.clone().map(|fix| (fix, v)))
+        .collect();
+    fixes.sort_by_key(|(fix, _)| (fix.byte_range.start, fix.byte_range.end));
+
+    let mut accepted: Vec<(Fix, Violation)> = Vec::new();
+    for (fix, violation) in fixes {
+        match accepted.last() {
+            Some((last, _)) if *last == fix => report.duplicates.push(violation),
+            Some((last, _)) if last.byte_range.end > fix.byte_range.start => {
+                report.conflicting.push(violation)
+            }
+            _ if fix.byte_range.end > content.len()
+                || !content.is_char_boundary(fix.byte_range.start)
+                || !content.is_char_boundary(fix.byte_range.end) =>
+            {
+                report.conflicting.push(violation)
+            }
+            _ => accepted.push((fix, violation)),
+        }
+    }
+
+    let mut fixed = content.to_string();
+    for (fix, violation) in accepted.into_iter().rev() {
+        let mut candidate = fixed.clone();
+        candidate.replace_range(fix.byte_range.clone(), &fix.replacement);
+        if syn::parse_file(&candidate).is_ok() {
+            fixed = candidate;
+            report.applied.push(violation);
+        } else {
+            report.rolled_back.push(violation);
+        }
+    }
+    report.applied.reverse();
+    (fixed, report)
+}
+
+/// Applies the fixes of `violations` to the files they were found in.
+pub fn apply_fixes(violations: &[Violation]) -> Result<FixReport> {
+    let mut by_file: BTreeMap<&str, Vec<Violation>> = BTreeMap::new();
+    for violation in violations.iter().filter(|v| v.fix.is_some()) {
+        by_file
+            .entry(&violation.file_name)
+            .or_default()
+            .push(violation.clone());
+    }
+
+    let mut report = FixReport::default();
+    for (path, violations) in by_file {
+        let content = fs::read_to_string(path)?;
+        let (fixed, file_report) = fix_content(&content, violations);
+        if !file_report.applied.is_empty() {
+            fs::write(path, fixed)?;
+        }
+        report.extend(file_report);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violation(content: &str, quote: &str, replacement: &str) -> Violation {
+        let mut violation = Violation::test("rule", "test.rs", content, quote);
+        violation.fix = Some(Fix {
+            byte_range: violation.byte_offset..violation.byte_offset + quote.len(),
+            replacement: replacement.to_string(),
+        });
+        violation
+    }
+
+    #[test]
+    fn test_fix_content() {
+        let content = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n";
+        let violations = vec![
+            violation(content, "fn add", "/// Adds two numbers.\nfn add"),
+            violation(content, "fn add", "/// Adds two numbers.\nfn add"),
+            violation(content, "add(a: i32", "sum(a: i32"),
+            violation(content, "a + b", "a + {"),
+        ];
+        let (fixed, report) = fix_content(content, violations);
+        assert_eq!(
+            fixed,
+            "/// Adds two numbers.\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n"
+        );
+        assert_eq!(report.applied.len(), 1);
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.conflicting.len(), 1);
+        assert_eq!(report.rolled_back.len(), 1);
+    }
+}
@@ -0,0 +1,39 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Files written under a fresh temporary directory, such as a small crate for the tests that
+/// read one, and removed when the fixture is dropped.
+pub struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    pub fn new(files: &[(&str, &[u8])]) -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "melange-fixture-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        Self { root }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::LazyLock,
+};
+
+use crate::rules::generic::{RuleWithCode, normalize_path};
+
+static FILE_HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\+\+\+ (?:b/)?(.+)$").unwrap());
+static HUNK_HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^@@ -\d+(?:,\d+)? \+(\d+)(?:,(\d+))? @@").unwrap());
+
+/// Lines touched by a diff, per file, as 1-based ranges of the new version of the file.
+#[derive(Debug, Default)]
+pub struct ChangedLines {
+    // The directory the paths of the diff are relative to.
+    root: PathBuf,
+    files: BTreeMap<String, Vec<Range<usize>>>,
+}
+
+impl ChangedLines {
+    /// Reads the changes between `rev` (or `HEAD`) and the working tree, or the index with `staged`.
+    pub fn from_git(rev: Option<&str>, staged: bool) -> Result<Self> {
+        let mut command = Command::new("git");
+        command.args([
+            "diff",
+            "--unified=0",
+            "--no-color",
+            "--no-ext-diff",
+            "--relative",
+        ]);
+        if staged {
+            command.arg("--cached");
+        }
+        if let Some(rev) = rev {
+            command.arg(rev);
+        }
+        let output = command.output().context("Failed to run git diff")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        // `--relative` gives paths relative to the working directory.
+        Ok(Self::parse(
+            &String::from_utf8_lossy(&output.stdout),
+            &std::env::current_dir()?,
+        ))
+    }
+
+    /// Parses a diff generated with `--unified=0`, whose paths are relative to `root`. A
+    /// deletion counts as a change of the line before it, which git gives as the start of a hunk
+    /// without new lines.
+    pub fn parse(diff: &str, root: &Path) -> Self {
+        let mut changed = Self {
+            root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            files: BTreeMap::new(),
+        };
+        let mut current: Option<String> = None;
+        for line in diff.lines() {
+            if line.starts_with("+++ ") {
+                current = FILE_HEADER
+                    .captures(line)
+                    .map(|caps| normalize_path(&caps[1]).to_string())
+                    .filter(|path| path != "/dev/null");
+            } else if let (Some(path), Some(caps)) = (&current, HUNK_HEADER.captures(line)) {
+                let start: usize = caps[1].parse().unwrap_or(1);
+                let count: usize = caps.get(2).map_or(1, |c| c.as_str().parse().unwrap_or(1));
+                let start = start.max(1);
+                changed
+                    .files
+                    .entry(path.clone())
+                    .or_default()
+                    .push(start..start + count.max(1));
+            }
+        }
+        changed
+    }
+
+    /// The changed files, with their paths relative to the working directory.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    /// The path of the diff for `path`, which is either absolute or relative to the root.
+    /// Both are resolved against the root, so that symlinks and `..` do not hide a change.
+    fn diff_path(&self, path: &str) -> String {
+        let absolute = self.root.join(path);
+        let absolute = absolute.canonicalize().unwrap_or(absolute);
+        match absolute.strip_prefix(&self.root) {
+            Ok(relative) => relative.to_string_lossy().into_owned(),
+            Err(_) => normalize_path(path).to_string(),
+        }
+    }
+
+    fn ranges(&self, path: &str) -> Option<&Vec<Range<usize>>> {
+        self.files.get(&self.diff_path(path))
+    }
+
+    pub fn touches_file(&self, path: &str) -> bool {
+        self.ranges(path).is_some()
+    }
+
+    /// Whether the item `rule` checks overlaps a change. Project rules, which check the whole
+    /// file, are kept for every touched file.
+    pub fn touches(&self, rule: &RuleWithCode) -> bool {
+        let Some(ranges) = self.ranges(rule.file_name()) else {
+            return false;
+        };
+        let item = rule.item_range();
+        let lines = rule.line_at(item.start)..rule.line_at(item.end) + 1;
+        ranges
+            .iter()
+            .any(|range| range.start < lines.end && lines.start < range.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixture;
+
+    #[test]
+    fn test_parse_diff() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -3,0 +4,2 @@ fn a() {
++    let x = 1;
++    let y = 2;
+@@ -10 +12 @@ fn b() {
+-    old();
++    new();
+@@ -20,3 +21,0 @@ fn c() {
+diff --git a/src/gone.rs b/src/gone.rs
+--- a/src/gone.rs
++++ /dev/null
+@@ -1,2 +0,0 @@
+";
+        let fixture = Fixture::new(&[("src/a.rs", b"fn a() {}\n"), ("src/b.rs", b"fn b() {}\n")]);
+        let changed = ChangedLines::parse(diff, fixture.path());
+        assert_eq!(changed.files().collect::<Vec<_>>(), vec!["src/a.rs"]);
+        // The deletion of old lines 20 to 22 marks line 21, the new line before them.
+        assert_eq!(changed.files["src/a.rs"], vec![4..6, 12..13, 21..22]);
+        assert!(changed.touches_file("./src/a.rs"));
+
+        // Absolute paths, as given with `-f`, match the paths of the diff.
+        let absolute = fixture.path().join("src/a.rs");
+        assert!(changed.touches_file(absolute.to_str().unwrap()));
+        let indirect = fixture.path().join("src/../src/a.rs");
+        assert!(changed.touches_file(indirect.to_str().unwrap()));
+        let unchanged = fixture.path().join("src/b.rs");
+        assert!(!changed.touches_file(unchanged.to_str().unwrap()));
+    }
+}
@@ -1,6 +1,11 @@
 pub mod cli;
 pub mod config;
-pub mod errors;
 pub mod engine;
+pub mod errors;
+pub mod fix;
+#[cfg(test)]
+pub(crate) mod fixture;
+pub mod git;
+pub mod lsp;
 pub mod parser;
-pub mod rules;
\ No newline at end of file
+pub mod rules;
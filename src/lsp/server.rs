@@ -0,0 +1,332 @@
+use anyhow::Result;
+use log::{debug, error};
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams,
+    CodeActionProviderCapability, Diagnostic, DiagnosticSeverity, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, Hover, HoverContents, HoverParams, HoverProviderCapability,
+    MarkupContent, MarkupKind, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncOptions, TextDocumentSyncSaveOptions, TextEdit,
+    Url, WorkspaceEdit,
+    notification::{
+        DidOpenTextDocument, DidSaveTextDocument, Notification as _, PublishDiagnostics,
+    },
+    request::{CodeActionRequest, HoverRequest, Request as _},
+};
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{
+    cli::lint::{file_rules, lint},
+    config::project_rules::ProjectRules,
+    engine::{
+        cache::{CACHE_FILE, ResultCache},
+        llm_engine::LlmEngine,
+    },
+    rules::{generic::Severity, violation::Violation},
+};
+
+struct Document {
+    content: String,
+    violations: Vec<Violation>,
+}
+
+struct Server {
+    connection: Connection,
+    llm: LlmEngine,
+    project_rules: ProjectRules,
+    batch: bool,
+    documents: HashMap<Url, Document>,
+}
+
+/// Serves the violations of the files opened in the editor as diagnostics, over stdio. Files
+/// are checked when they are opened and saved, with unchanged items answered from the cache.
+pub async fn run(llm: LlmEngine, project_rules: ProjectRules, batch: bool) -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Options(
+            TextDocumentSyncOptions {
+                open_close: Some(true),
+                save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                ..Default::default()
+            },
+        )),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut server = Server {
+        connection,
+        llm: llm.with_cache(ResultCache::load(CACHE_FILE)?),
+        project_rules,
+        batch,
+        documents: HashMap::new(),
+    };
+    server.main_loop().await?;
+    drop(server);
+    io_threads.join()?;
+    Ok(())
+}
+
+impl Server {
+    async fn main_loop(&mut self) -> Result<()> {
+        loop {
+            // Messages are waited for on a blocking thread, so that the runtime is not held up.
+            let receiver = self.connection.receiver.clone();
+            let Ok(message) = tokio::task::spawn_blocking(move || receiver.recv()).await? else {
+                break;
+            };
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    self.handle_request(request)?;
+                }
+                Message::Notification(notification) => {
+                    let uri = match notification.method.as_str() {
+                        DidOpenTextDocument::METHOD => {
+                            notification
+                                .extract::<DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)?
+                                .text_document
+                                .uri
+                        }
+                        DidSaveTextDocument::METHOD => {
+                            notification
+                                .extract::<DidSaveTextDocumentParams>(DidSaveTextDocument::METHOD)?
+                                .text_document
+                                .uri
+                        }
+                        _ => continue,
+                    };
+                    if let Err(e) = self.check(uri).await {
+                        error!("{:#}", e);
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, request: Request) -> Result<()> {
+        let response = match request.method.as_str() {
+            HoverRequest::METHOD => {
+                let (id, params) = request.extract::<HoverParams>(HoverRequest::METHOD)?;
+                Response::new_ok(id, self.hover(params))
+            }
+            CodeActionRequest::METHOD => {
+                let (id, params) =
+                    request.extract::<CodeActionParams>(CodeActionRequest::METHOD)?;
+                Response::new_ok(id, self.code_actions(params))
+            }
+            _ => Response::new_err(
+                request.id,
+                lsp_server::ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request {}", request.method),
+            ),
+        };
+        self.connection.sender.send(Message::Response(response))?;
+        Ok(())
+    }
+
+    /// Checks the file behind `uri` and publishes its violations.
+    async fn check(&mut self, uri: Url) -> Result<()> {
+        let Some(path) = uri
+            .to_file_path()
+            .ok()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        else {
+            return Ok(());
+        };
+        let path = path.to_string_lossy().into_owned();
+        debug!("Checking {}", path);
+        let content = fs::read_to_string(&path)?;
+        // Rules relating other files to the checked ones see the saved code.
+        self.project_rules
+            .context_sources()
+            .invalidate(Path::new(&path));
+        let (rules, suppressions) = file_rules(&path, &self.project_rules, self.batch, None)?;
+        let files = [(path, rules)];
+        let outcome = lint(&self.llm, &files).await;
+        self.llm.save_cache(CACHE_FILE)?;
+
+        let mut diagnostics: Vec<_> = outcome
+            .violations
+            .iter()
+            .map(|violation| Diagnostic {
+                range: range(&content, violation),
+                severity: Some(match violation.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                    Severity::Info => DiagnosticSeverity::INFORMATION,
+                }),
+                source: Some("melange".to_string()),
+                message: violation.explanation.clone(),
+                ..Default::default()
+            })
+            .collect();
+        // Suppressions that silence nothing, or that are ignored, show on their comment.
+        diagnostics.extend(
+            suppressions
+                .warnings()
+                .into_iter()
+                .map(|(suppression, warning)| Diagnostic {
+                    range: line_range(&content, suppression.line),
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    source: Some("melange".to_string()),
+                    message: warning,
+                    ..Default::default()
+                }),
+        );
+        let params = PublishDiagnosticsParams {
+            uri: uri.clone(),
+            diagnostics,
+            version: None,
+        };
+        self.connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                PublishDiagnostics::METHOD.to_string(),
+                params,
+            )))?;
+        self.documents.insert(
+            uri,
+            Document {
+                content,
+                violations: outcome.violations,
+            },
+        );
+        Ok(())
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let position = params.text_document_position_params;
+        let document = self.documents.get(&position.text_document.uri)?;
+        let (violation, range) = document
+            .violations
+            .iter()
+            .map(|v| (v, range(&document.content, v)))
+            .find(|(_, range)| contains(range, position.position))?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!(
+                    "**melange rule:** {}\n\n{}",
+                    violation.rule, violation.explanation
+                ),
+            }),
+            range: Some(range),
+        })
+    }
+
+    fn code_actions(&self, params: CodeActionParams) -> Vec<CodeActionOrCommand> {
+        let uri = params.text_document.uri;
+        let Some(document) = self.documents.get(&uri) else {
+            return Vec::new();
+        };
+        document
+            .violations
+            .iter()
+            .filter(|v| overlaps(&range(&document.content, v), &params.range))
+            .filter_map(|violation| {
+                let fix = violation.fix.as_ref()?;
+                let edit = TextEdit {
+                    range: Range {
+                        start: position(&document.content, fix.byte_range.start),
+                        end: position(&document.content, fix.byte_range.end),
+                    },
+                    new_text: fix.replacement.clone(),
+                };
+                Some(CodeActionOrCommand::CodeAction(CodeAction {
+                    title: format!("Fix: {}", violation.rule),
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }))
+            })
+            .collect()
+    }
+}
+
+/// LSP position of a byte offset, with the character counted in UTF-16 code units.
+fn position(content: &str, byte_offset: usize) -> Position {
+    let before = &content[..byte_offset.min(content.len())];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/// The span of the quoted code, or the rest of the line when the quote is not verbatim.
+fn range(content: &str, violation: &Violation) -> Range {
+    let start = violation.byte_offset.min(content.len());
+    let end = match &violation.fix {
+        Some(fix) => fix.byte_range.end,
+        None if content[start..].starts_with(violation.quote.trim()) => {
+            start + violation.quote.trim().len()
+        }
+        None => content[start..]
+            .find('\n')
+            .map_or(content.len(), |idx| start + idx),
+    };
+    Range {
+        start: position(content, start),
+        end: position(content, end),
+    }
+}
+
+/// The range of line `line` of `content`, from 1.
+fn line_range(content: &str, line: usize) -> Range {
+    let start: usize = content
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let end = content[start..]
+        .find('\n')
+        .map_or(content.len(), |idx| start + idx);
+    Range {
+        start: position(content, start),
+        end: position(content, end),
+    }
+}
+
+fn contains(range: &Range, position: Position) -> bool {
+    range.start <= position && position <= range.end
+}
+
+fn overlaps(a: &Range, b: &Range) -> bool {
+    a.start <= b.end && b.start <= a.end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_positions() {
+        let content = "fn a() {}\nlet é = \"😀\"; x.unwrap();\n";
+        assert_eq!(position(content, 0), Position::new(0, 0));
+        assert_eq!(position(content, 10), Position::new(1, 0));
+        // Characters are counted in UTF-16 code units, two for the emoji, one for `é`.
+        let offset = content.find("x.unwrap").unwrap();
+        assert_eq!(position(content, offset), Position::new(1, 14));
+        assert_eq!(position(content, usize::MAX), Position::new(2, 0));
+
+        let violation = Violation::test("no-unwrap", "a.rs", content, "x.unwrap()");
+        assert_eq!(
+            range(content, &violation),
+            Range::new(Position::new(1, 14), Position::new(1, 24))
+        );
+        assert_eq!(
+            line_range(content, 2),
+            Range::new(Position::new(1, 0), Position::new(1, 25))
+        );
+    }
+}
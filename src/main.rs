@@ -2,24 +2,267 @@ use anyhow::Result;
 use clap::Parser;
 use log::debug;
 use melange::{
-    cli::args::Cli, engine::llm_engine::LlmEngine, parser::rust_parser::parse_rust_file,
+    cli::{
+        args::{BaselineAction, Cli, Command, OutputFormat, RulesAction},
+        dry_run,
+        eval::{eval, load_dataset, print_reports},
+        explain::explain,
+        lint::{LintOutcome, file_rules, lint},
+        review::review,
+        test_rules::{print_results, test_rules},
+        watch,
+    },
+    config::project_rules::ProjectRules,
+    engine::{
+        cache::{CACHE_FILE, ResultCache},
+        llm_engine::{LlmConfig, LlmEngine},
+    },
+    errors::melange_errors::{EXIT_AMBIGUOUS_RULES, EXIT_BUDGET_EXCEEDED},
+    fix::apply::apply_fixes,
+    lsp,
+    rules::{
+        baseline::{BASELINE_FILE, Baseline},
+        suppression::SuppressionReport,
+    },
 };
 
+/// The config at `path`, with the budget limits given on the command line.
+fn load_config(path: &str, cli: &Cli) -> Result<LlmConfig> {
+    let mut config = LlmConfig::from_file(path)?;
+    config.max_cost_usd = cli.max_cost_usd.or(config.max_cost_usd);
+    config.max_requests = cli.max_requests.or(config.max_requests);
+    Ok(config)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
     debug!("Starting up");
 
     let cli = Cli::parse();
-    let llm = LlmEngine::from_config("melange-config.toml").unwrap();
-    // let project_rules = config::project_rules::load_project_rules()?;
-    for path in cli.files {
-        debug!("Checking file: {}", path);
-        let rules = parse_rust_file(&path);
-        for rule in rules {
-            let response = llm.query_with_rule(rule).await.unwrap();
-            println!("{}", response);
+    if let Some(Command::Eval { dataset, configs }) = &cli.command {
+        let dataset = load_dataset(dataset)?;
+        let mut reports = Vec::new();
+        for config_path in configs {
+            let llm = LlmEngine::new(load_config(config_path, &cli)?)?;
+            reports.push(eval(config_path, &llm, &dataset).await);
+        }
+        match cli.format {
+            OutputFormat::Text => print_reports(&reports),
+            OutputFormat::Json => {
+                let reports: Vec<_> = reports
+                    .iter()
+                    .map(|report| {
+                        serde_json::json!({
+                            "report": report,
+                            "precision": report.precision(),
+                            "recall": report.recall(),
+                            "f1": report.f1(),
+                        })
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&reports)?);
+            }
+        }
+        if reports
+            .iter()
+            .any(|report| report.budget_exceeded.is_some())
+        {
+            std::process::exit(EXIT_BUDGET_EXCEEDED);
+        }
+        return Ok(());
+    }
+    let config_path = "melange-config.toml";
+    let mut config = load_config(config_path, &cli)?;
+    let project_rules = ProjectRules::new(
+        config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| rule.project_rule(config_path, idx))
+            .collect(),
+    )
+    .with_own_templates(config.templates.rules.keys().cloned());
+    if let Some(Command::Rules {
+        action: RulesAction::Explain { file },
+    }) = &cli.command
+    {
+        return explain(file, &project_rules);
+    }
+    if let Some(Command::TestRules { runs, threshold }) = cli.command {
+        let rules = std::mem::take(&mut config.rules);
+        let outcome = test_rules(&LlmEngine::new(config)?, &rules, runs).await?;
+        let all_pass = print_results(&outcome, threshold);
+        if outcome.budget_exceeded.is_some() {
+            std::process::exit(EXIT_BUDGET_EXCEEDED);
+        }
+        if !all_pass {
+            std::process::exit(EXIT_AMBIGUOUS_RULES);
+        }
+        return Ok(());
+    }
+    if let Some(Command::Lsp) = cli.command {
+        return lsp::server::run(LlmEngine::new(config)?, project_rules, cli.batch).await;
+    }
+    if let Some(Command::Watch { paths }) = &cli.command {
+        let llm = LlmEngine::new(config)?.with_cache(ResultCache::load(CACHE_FILE)?);
+        return watch::run(&llm, paths, &project_rules, cli.batch).await;
+    }
+
+    let changed = cli.changed_lines()?;
+    let mut files = Vec::new();
+    let mut suppressions = SuppressionReport::default();
+    for path in cli.source_files(changed.as_ref())? {
+        let (rules, file_suppressions) =
+            file_rules(&path, &project_rules, cli.batch, changed.as_ref())?;
+        suppressions.extend(file_suppressions);
+        files.push((path, rules));
+    }
+
+    if cli.dry_run {
+        dry_run::run(&config, &files);
+        suppressions.print();
+        return Ok(());
+    }
+
+    let llm = LlmEngine::new(config)?.with_cache(ResultCache::load(CACHE_FILE)?);
+    let outcome = lint(&llm, &files).await;
+    llm.save_cache(CACHE_FILE)?;
+    let evaluated = outcome.evaluated_rules();
+    let LintOutcome {
+        violations,
+        budget_exceeded,
+        not_evaluated,
+        failed,
+        ..
+    } = outcome;
+
+    if let Some(Command::Baseline { action }) = &cli.command {
+        // A baseline would miss the violations of the checks that were not evaluated, and pruning
+        // would take their entries for fixed ones.
+        if let Some(reason) = &budget_exceeded {
+            eprintln!(
+                "melange: budget exceeded ({}), {} rule checks not evaluated, {} left unchanged",
+                reason,
+                not_evaluated.len() + failed.len(),
+                BASELINE_FILE
+            );
+            std::process::exit(EXIT_BUDGET_EXCEEDED);
+        }
+        anyhow::ensure!(
+            failed.is_empty(),
+            "not updating {}: {} rule checks failed",
+            BASELINE_FILE,
+            failed.len()
+        );
+        match action {
+            BaselineAction::Create => {
+                let baseline = Baseline::from_violations(&violations);
+                baseline.save(BASELINE_FILE)?;
+                println!(
+                    "melange: recorded {} violations in {}",
+                    baseline.len(),
+                    BASELINE_FILE
+                );
+            }
+            BaselineAction::Prune => {
+                let mut baseline = Baseline::load(BASELINE_FILE)?;
+                let removed = baseline.prune(&violations, &evaluated);
+                baseline.save(BASELINE_FILE)?;
+                println!(
+                    "melange: removed {} fixed violations from {}",
+                    removed, BASELINE_FILE
+                );
+            }
+        }
+        return Ok(());
+    }
+    let (violations, baselined) = Baseline::load(BASELINE_FILE)?.filter(violations);
+
+    if let Some(Command::Review) = cli.command {
+        if let Some(reason) = &budget_exceeded {
+            eprintln!(
+                "melange: budget exceeded ({}), reviewing the violations found so far",
+                reason
+            );
+        }
+        review(violations)?;
+        llm.usage_report().print_table();
+        if budget_exceeded.is_some() {
+            std::process::exit(EXIT_BUDGET_EXCEEDED);
         }
+        return Ok(());
+    }
+
+    if cli.fix {
+        let report = apply_fixes(&violations)?;
+        eprintln!(
+            "melange: applied {} fixes, skipped {} duplicate and {} overlapping fixes, rolled back {} fixes that broke parsing",
+            report.applied.len(),
+            report.duplicates.len(),
+            report.conflicting.len(),
+            report.rolled_back.len()
+        );
+    }
+
+    let usage = llm.usage_report();
+    match cli.format {
+        OutputFormat::Text => {
+            violations.iter().for_each(|v| println!("{}\n", v));
+            usage.print_table();
+            suppressions.print();
+            if baselined > 0 {
+                println!(
+                    "melange: {} violations in the baseline not reported",
+                    baselined
+                );
+            }
+            if let Some(reason) = &budget_exceeded {
+                eprintln!(
+                    "melange: budget exceeded ({}), {} rule checks not evaluated:",
+                    reason,
+                    not_evaluated.len()
+                );
+                for rule in &not_evaluated {
+                    for description in rule.rules() {
+                        eprintln!(
+                            "  {}:{} {} {}: {}",
+                            rule.file_name(),
+                            rule.line_at(rule.byte_range().start),
+                            rule.item_kind(),
+                            rule.item_name(),
+                            description
+                        );
+                    }
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let not_evaluated: Vec<_> = not_evaluated
+                .iter()
+                .map(|rule| {
+                    serde_json::json!({
+                        "file_name": rule.file_name(),
+                        "line": rule.line_at(rule.byte_range().start),
+                        "item_kind": rule.item_kind(),
+                        "item_name": rule.item_name(),
+                        "rules": rule.rules(),
+                    })
+                })
+                .collect();
+            let output = serde_json::json!({
+                "violations": violations,
+                "usage": usage,
+                "suppressions": suppressions,
+                "baselined": baselined,
+                "budget_exceeded": budget_exceeded,
+                "not_evaluated": not_evaluated,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+    }
+    if budget_exceeded.is_some() {
+        std::process::exit(EXIT_BUDGET_EXCEEDED);
     }
     Ok(())
 }
@@ -0,0 +1,269 @@
+use anyhow::Result;
+use globset::{GlobBuilder, GlobSetBuilder};
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use syn::{ImplItem, Item, spanned::Spanned};
+
+use crate::{
+    cli::args::collect_rust_files,
+    rules::generic::{ContextFile, normalize_path},
+};
+
+/// Most related code added to a prompt, in bytes. Past it, only the relevant items of the
+/// related files are included.
+const MAX_CONTEXT_BYTES: usize = 16 * 1024;
+
+/// The Rust files under the working directory that project rules relate the checked files to,
+/// listed and read once per run rather than once per checked file.
+#[derive(Debug)]
+pub struct ContextSources {
+    root: PathBuf,
+    // Rust files under `root`, relative to it, listed on first use.
+    files: Mutex<Option<Vec<String>>>,
+    // Contents read so far, `None` for the unreadable files.
+    contents: Mutex<HashMap<String, Option<Arc<String>>>>,
+}
+
+impl Default for ContextSources {
+    fn default() -> Self {
+        Self::new(".")
+    }
+}
+
+impl ContextSources {
+    /// The sources under `root`, with their paths relative to it.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            files: Mutex::new(None),
+            contents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The files matching `globs`, other than `file` itself. Unreadable files are skipped with a
+    /// warning.
+    pub fn matching(&self, globs: &[String], file: &str) -> Result<Vec<(String, Arc<String>)>> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+        }
+        let set = builder.build()?;
+        let file = normalize_path(file);
+        let mut files = self.files.lock().unwrap();
+        if files.is_none() {
+            let mut paths = Vec::new();
+            collect_rust_files(&self.root, &mut paths)?;
+            *files = Some(
+                paths
+                    .iter()
+                    .map(|path| {
+                        let path = Path::new(path);
+                        path.strip_prefix(&self.root)
+                            .unwrap_or(path)
+                            .to_string_lossy()
+                            .into_owned()
+                    })
+                    .collect(),
+            );
+        }
+        let mut contents = self.contents.lock().unwrap();
+        let mut sources = Vec::new();
+        for path in files.iter().flatten() {
+            if path == file || !set.is_match(path) {
+                continue;
+            }
+            let content = contents.entry(path.clone()).or_insert_with(|| {
+                fs::read_to_string(self.root.join(path))
+                    .inspect_err(|e| eprintln!("melange: skipping context file {}: {}", path, e))
+                    .ok()
+                    .map(Arc::new)
+            });
+            if let Some(content) = content {
+                sources.push((path.clone(), Arc::clone(content)));
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Forgets what was read of `path`, after it changed, and the list of files when `path` is
+    /// not on it.
+    pub fn invalidate(&self, path: &Path) {
+        let root = std::env::current_dir().unwrap_or_default().join(&self.root);
+        let path = path
+            .strip_prefix(&root)
+            .or_else(|_| path.strip_prefix(&self.root))
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned();
+        let path = normalize_path(&path);
+        self.contents.lock().unwrap().remove(path);
+        let mut files = self.files.lock().unwrap();
+        if files
+            .as_ref()
+            .is_some_and(|files| !files.iter().any(|f| f == path))
+        {
+            *files = None;
+        }
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Whether `word` appears in `text` as a whole identifier.
+pub(crate) fn mentions(text: &str, word: &str) -> bool {
+    !word.is_empty()
+        && text.match_indices(word).any(|(idx, _)| {
+            !text[..idx].ends_with(is_ident_char)
+                && !text[idx + word.len()..].starts_with(is_ident_char)
+        })
+}
+
+/// Named items of a file, looking into modules and impl blocks.
+pub(crate) fn named_items(items: &[Item], out: &mut Vec<(String, Range<usize>)>) {
+    for item in items {
+        let name = match item {
+            Item::Mod(item_mod) => {
+                if let Some((_, nested)) = &item_mod.content {
+                    named_items(nested, out);
+                }
+                continue;
+            }
+            Item::Impl(item_impl) => {
+                for impl_item in &item_impl.items {
+                    if let ImplItem::Fn(method) = impl_item {
+                        out.push((method.sig.ident.to_string(), impl_item.span().byte_range()));
+                    }
+                }
+                continue;
+            }
+            Item::Fn(i) => i.sig.ident.to_string(),
+            Item::Struct(i) => i.ident.to_string(),
+            Item::Enum(i) => i.ident.to_string(),
+            Item::Trait(i) => i.ident.to_string(),
+            Item::Const(i) => i.ident.to_string(),
+            Item::Static(i) => i.ident.to_string(),
+            Item::Type(i) => i.ident.to_string(),
+            _ => continue,
+        };
+        out.push((name, item.span().byte_range()));
+    }
+}
+
+/// The code of `sources` related to the checked `code`, whose items are named `names`: the
+/// whole files when they fit in the context budget, and otherwise the items that mention one
+/// of `names` or are named in `code`.
+pub fn related_context(
+    sources: &[(String, Arc<String>)],
+    names: &[&str],
+    code: &str,
+) -> Vec<ContextFile> {
+    let whole = |(path, content): &(String, Arc<String>)| {
+        ContextFile::whole(path.clone(), Arc::clone(content))
+    };
+    if sources
+        .iter()
+        .map(|(_, content)| content.len())
+        .sum::<usize>()
+        <= MAX_CONTEXT_BYTES
+    {
+        return sources.iter().map(whole).collect();
+    }
+    let mut budget = MAX_CONTEXT_BYTES;
+    let mut context = Vec::new();
+    for (path, content) in sources {
+        let Ok(syntax_tree) = syn::parse_file(content) else {
+            continue;
+        };
+        let mut items = Vec::new();
+        named_items(&syntax_tree.items, &mut items);
+        let ranges: Vec<_> = items
+            .into_iter()
+            .filter(|(name, range)| {
+                mentions(code, name) || names.iter().any(|n| mentions(&content[range.clone()], n))
+            })
+            .map(|(_, range)| range)
+            // An item too large for what is left of the budget makes room for smaller ones.
+            .filter(|range| match budget.checked_sub(range.len()) {
+                Some(left) => {
+                    budget = left;
+                    true
+                }
+                None => false,
+            })
+            .collect();
+        if !ranges.is_empty() {
+            context.push(ContextFile {
+                path: path.clone(),
+                content: Arc::clone(content),
+                ranges,
+            });
+        }
+    }
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixture;
+
+    #[test]
+    fn test_related_context() {
+        let filler = format!("// {}\n", "x".repeat(MAX_CONTEXT_BYTES));
+        let tests = format!(
+            "{}mod tests {{\n    fn test_open() {{ open_detour(); }}\n    fn test_close() {{}}\n}}\n",
+            filler
+        );
+        let sources = vec![("e2e/file.rs".to_string(), Arc::new(tests))];
+        let context = related_context(&sources, &["open_detour"], "fn open_detour() {}");
+        assert_eq!(context.len(), 1);
+        assert_eq!(context[0].excerpt(), "fn test_open() { open_detour(); }");
+
+        // An item too large for the budget does not keep the smaller ones after it out.
+        let large = format!(
+            "fn test_large() {{\n    open_detour();\n    {}}}\nfn test_small() {{ open_detour(); }}\n",
+            filler
+        );
+        let sources = vec![("e2e/file.rs".to_string(), Arc::new(large))];
+        let context = related_context(&sources, &["open_detour"], "fn open_detour() {}");
+        assert_eq!(context[0].excerpt(), "fn test_small() { open_detour(); }");
+
+        assert!(mentions("a(open_detour)", "open_detour"));
+        assert!(!mentions("reopen_detour", "open_detour"));
+    }
+
+    #[test]
+    fn test_context_sources() {
+        let fixture = Fixture::new(&[
+            ("src/lib.rs", b"pub mod hooks;\n"),
+            ("src/hooks.rs", b"pub fn open_detour() {}\n"),
+            ("e2e/file.rs", b"fn test_open() {}\n"),
+            ("e2e/broken.rs", b"fn \xff() {}\n"),
+        ]);
+        let sources = ContextSources::new(fixture.path());
+        let globs = ["e2e/*.rs".to_string(), "src/*.rs".to_string()];
+        // The unreadable file is skipped, and the checked file is left out.
+        let matching = sources.matching(&globs, "./src/hooks.rs").unwrap();
+        let paths: Vec<_> = matching.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["e2e/file.rs", "src/lib.rs"]);
+
+        // Files are read once, until they are invalidated.
+        fs::write(fixture.path().join("e2e/file.rs"), "fn test_close() {}\n").unwrap();
+        fs::write(fixture.path().join("e2e/new.rs"), "fn test_new() {}\n").unwrap();
+        let matching = sources.matching(&globs, "src/hooks.rs").unwrap();
+        assert_eq!(matching[0].1.as_str(), "fn test_open() {}\n");
+        assert_eq!(matching.len(), 2);
+        sources.invalidate(&fixture.path().join("e2e/file.rs"));
+        sources.invalidate(&fixture.path().join("e2e/new.rs"));
+        let matching = sources.matching(&globs, "src/hooks.rs").unwrap();
+        assert_eq!(matching[0].1.as_str(), "fn test_close() {}\n");
+        assert_eq!(matching[1].0, "e2e/new.rs");
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use syn::{File, Item, Type, UseTree, spanned::Spanned, visit::Visit};
+
+use crate::{engine::tokens::estimate_tokens, rules::generic::ContextFile};
+
+/// Most tokens of type and trait definitions added to the prompt of a function.
+const MAX_DEFINITION_TOKENS: usize = 1024;
+
+/// The paths a function refers to, in its signature and body, in order of first appearance.
+#[derive(Default)]
+pub struct PathNames {
+    pub paths: Vec<Vec<String>>,
+    // Path of the type `Self` stands for, in the methods of an impl.
+    self_type: Option<Vec<String>>,
+}
+
+impl PathNames {
+    /// The paths of a method of an impl of `self_type`, with `Self` resolved to it.
+    pub fn with_self_type(self_type: &Type) -> Self {
+        Self {
+            paths: Vec::new(),
+            self_type: match self_type {
+                Type::Path(type_path) => Some(
+                    type_path
+                        .path
+                        .segments
+                        .iter()
+                        .map(|s| s.ident.to_string())
+                        .collect(),
+                ),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for PathNames {
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        let mut segments: Vec<_> = path.segments.iter().map(|s| s.ident.to_string()).collect();
+        if segments[0] == "Self"
+            && let Some(self_type) = &self.self_type
+        {
+            segments.splice(..1, self_type.iter().cloned());
+        }
+        if !self.paths.contains(&segments) {
+            self.paths.push(segments);
+        }
+        syn::visit::visit_path(self, path);
+    }
+}
+
+/// Adds the `use` declarations of `tree` to `imports`, by the name they bring in scope.
+fn imports(tree: &UseTree, prefix: &[String], out: &mut HashMap<String, Vec<String>>) {
+    let with = |name: &syn::Ident| {
+        let mut path = prefix.to_vec();
+        path.push(name.to_string());
+        path
+    };
+    match tree {
+        UseTree::Path(p) => imports(&p.tree, &with(&p.ident), out),
+        UseTree::Name(n) if n.ident == "self" => {
+            if let Some(last) = prefix.last() {
+                out.insert(last.clone(), prefix.to_vec());
+            }
+        }
+        UseTree::Name(n) => {
+            out.insert(n.ident.to_string(), with(&n.ident));
+        }
+        UseTree::Rename(r) => {
+            out.insert(r.rename.to_string(), with(&r.ident));
+        }
+        UseTree::Group(g) => g.items.iter().for_each(|tree| imports(tree, prefix, out)),
+        UseTree::Glob(_) => {}
+    }
+}
+
+/// The struct, enum, union, trait or type alias named `name` among `items` and their inline
+/// modules.
+fn find_definition(items: &[Item], name: &str) -> Option<Range<usize>> {
+    items.iter().find_map(|item| match item {
+        Item::Struct(i) if i.ident == name => Some(item.span().byte_range()),
+        Item::Enum(i) if i.ident == name => Some(item.span().byte_range()),
+        Item::Union(i) if i.ident == name => Some(item.span().byte_range()),
+        Item::Trait(i) if i.ident == name => Some(item.span().byte_range()),
+        Item::Type(i) if i.ident == name => Some(item.span().byte_range()),
+        Item::Mod(i) => i
+            .content
+            .as_ref()
+            .and_then(|(_, nested)| find_definition(nested, name)),
+        _ => None,
+    })
+}
+
+/// Resolves the types and traits the functions of a file refer to against the items of its
+/// crate, following the `use` declarations of the file.
+pub struct Definitions {
+    file: String,
+    content: Arc<String>,
+    items: Vec<Item>,
+    // The crate's `src` directory, and the module path of the file under it.
+    src_dir: Option<PathBuf>,
+    module: Vec<String>,
+    imports: HashMap<String, Vec<String>>,
+    // Other files of the crate, parsed on first use.
+    parsed: HashMap<PathBuf, Option<(Arc<String>, File)>>,
+}
+
+impl Definitions {
+    pub fn new(file_path: &str, content: &Arc<String>, syntax_tree: &File) -> Self {
+        let path = Path::new(file_path);
+        let src_dir = path
+            .ancestors()
+            .find(|dir| dir.file_name().is_some_and(|name| name == "src"))
+            .map(Path::to_path_buf);
+        let module = match &src_dir {
+            Some(src_dir) => {
+                let relative = path
+                    .strip_prefix(src_dir)
+                    .unwrap_or(path)
+                    .with_extension("");
+                let mut module: Vec<_> = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect();
+                if matches!(
+                    module.last().map(String::as_str),
+                    Some("mod" | "lib" | "main")
+                ) {
+                    module.pop();
+                }
+                module
+            }
+            None => Vec::new(),
+        };
+        let mut imported = HashMap::new();
+        for item in &syntax_tree.items {
+            if let Item::Use(item_use) = item {
+                imports(&item_use.tree, &[], &mut imported);
+            }
+        }
+        Self {
+            file: file_path.trim_start_matches("./").to_string(),
+            content: Arc::clone(content),
+            items: syntax_tree.items.clone(),
+            src_dir,
+            module,
+            imports: imported,
+            parsed: HashMap::new(),
+        }
+    }
+
+    /// Turns a path as written in the file into a path from the crate root, when it points
+    /// into the crate.
+    fn crate_path(&self, segments: &[String]) -> Option<Vec<String>> {
+        let (first, rest) = segments.split_first()?;
+        match self.imports.get(first) {
+            Some(imported) if !matches!(first.as_str(), "crate" | "self" | "super") => {
+                let mut path = self.absolute_path(imported)?;
+                path.extend_from_slice(rest);
+                Some(path)
+            }
+            _ => self.absolute_path(segments),
+        }
+    }
+
+    /// Resolves the start of a path that does not go through an import: `crate`, `self`,
+    /// `super` or a module declared in the file.
+    fn absolute_path(&self, segments: &[String]) -> Option<Vec<String>> {
+        let (first, rest) = segments.split_first()?;
+        let mut path = match first.as_str() {
+            "crate" => Vec::new(),
+            "self" => self.module.clone(),
+            "super" => self.module[..self.module.len().checked_sub(1)?].to_vec(),
+            name if self
+                .items
+                .iter()
+                .any(|item| matches!(item, Item::Mod(m) if m.ident == name)) =>
+            {
+                return Some([self.module.as_slice(), segments].concat());
+            }
+            _ => return None,
+        };
+        path.extend_from_slice(rest);
+        Some(path)
+    }
+
+    /// The file of a module of the crate.
+    fn module_file(&self, module: &[String]) -> Option<PathBuf> {
+        let src_dir = self.src_dir.as_ref()?;
+        if module.is_empty() {
+            return ["lib.rs", "main.rs"]
+                .iter()
+                .map(|root| src_dir.join(root))
+                .find(|file| file.exists());
+        }
+        let dir = module.iter().fold(src_dir.clone(), |dir, m| dir.join(m));
+        [dir.with_extension("rs"), dir.join("mod.rs")]
+            .into_iter()
+            .find(|file| file.exists())
+    }
+
+    /// The definition a path refers to, as its file, the file content and the item range.
+    fn resolve(&mut self, segments: &[String]) -> Option<(String, Arc<String>, Range<usize>)> {
+        let first = segments.first()?;
+        if !self.imports.contains_key(first)
+            && let Some(range) = find_definition(&self.items, first)
+        {
+            return Some((self.file.clone(), Arc::clone(&self.content), range));
+        }
+        let path = self.crate_path(segments)?;
+        // The path may go on past the definition, as in `Severity::Error`.
+        for end in (0..path.len()).rev() {
+            let Some(file) = self.module_file(&path[..end]) else {
+                continue;
+            };
+            let parsed = self.parsed.entry(file.clone()).or_insert_with(|| {
+                let content = fs::read_to_string(&file).ok()?;
+                let syntax_tree = syn::parse_file(&content).ok()?;
+                Some((Arc::new(content), syntax_tree))
+            });
+            if let Some((content, syntax_tree)) = parsed
+                && let Some(range) = find_definition(&syntax_tree.items, &path[end])
+            {
+                let file = file.to_string_lossy().trim_start_matches("./").to_string();
+                return Some((file, Arc::clone(content), range));
+            }
+        }
+        None
+    }
+
+    /// The definitions of the types and traits in `paths`, grouped by file, leaving out those
+    /// overlapping `item_range` in this file and those past the token budget.
+    pub fn context(
+        &mut self,
+        paths: &[Vec<String>],
+        item_range: &Range<usize>,
+    ) -> Vec<ContextFile> {
+        let mut budget = MAX_DEFINITION_TOKENS;
+        let mut context: Vec<ContextFile> = Vec::new();
+        for segments in paths {
+            let Some((file, content, range)) = self.resolve(segments) else {
+                continue;
+            };
+            if file == self.file && range.start < item_range.end && item_range.start < range.end {
+                continue;
+            }
+            let tokens = estimate_tokens("", &content[range.clone()]);
+            match context.iter_mut().find(|c| c.path == file) {
+                Some(c) if c.ranges.contains(&range) => continue,
+                _ if tokens > budget => continue,
+                Some(c) => c.ranges.push(range),
+                None => context.push(ContextFile {
+                    path: file,
+                    content,
+                    ranges: vec![range],
+                }),
+            }
+            budget -= tokens;
+        }
+        for c in &mut context {
+            c.ranges.sort_by_key(|range| range.start);
+        }
+        context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixture;
+    use syn::ImplItem;
+
+    const ENGINE: &str = r#"use crate::{errors::MelangeError, rules::generic::Rule};
+use std::fs;
+
+pub struct Engine {
+    rules: Vec<Rule>,
+}
+
+impl Engine {
+    pub fn load(path: &str) -> Result<Self, MelangeError> {
+        fs::read_to_string(path).map_err(|_| MelangeError::BudgetExceeded)?;
+        Ok(Self { rules: Vec::new() })
+    }
+}
+"#;
+
+    #[test]
+    fn test_definitions() {
+        let fixture = Fixture::new(&[
+            (
+                "src/lib.rs",
+                b"pub mod engine;\npub mod errors;\npub mod rules;\n",
+            ),
+            (
+                "src/errors.rs",
+                b"#[derive(Debug)]\npub enum MelangeError {\n    BudgetExceeded,\n}\n",
+            ),
+            ("src/rules/mod.rs", b"pub mod generic;\n"),
+            (
+                "src/rules/generic.rs",
+                b"pub struct Rule {\n    pub id: String,\n}\n",
+            ),
+            ("src/engine.rs", ENGINE.as_bytes()),
+        ]);
+        let file = fixture.path().join("src/engine.rs");
+        let content = Arc::new(ENGINE.to_string());
+        let syntax_tree = syn::parse_file(&content).unwrap();
+        let mut definitions = Definitions::new(file.to_str().unwrap(), &content, &syntax_tree);
+        let path = |p: &str| p.split("::").map(str::to_string).collect::<Vec<_>>();
+        let relative = |context: &[ContextFile]| -> Vec<String> {
+            context
+                .iter()
+                .map(|c| {
+                    let path = Path::new(&c.path).strip_prefix(fixture.path()).unwrap();
+                    path.to_string_lossy().into_owned()
+                })
+                .collect()
+        };
+
+        let context = definitions.context(
+            &[
+                path("Rule"),
+                path("MelangeError::BudgetExceeded"),
+                path("Engine"),
+                path("std::fs"),
+            ],
+            &(0..0),
+        );
+        assert_eq!(
+            relative(&context),
+            ["src/rules/generic.rs", "src/errors.rs", "src/engine.rs"]
+        );
+        assert_eq!(
+            context[0].excerpt(),
+            "pub struct Rule {\n    pub id: String,\n}"
+        );
+        assert!(
+            context[1]
+                .excerpt()
+                .starts_with("#[derive(Debug)]\npub enum MelangeError {")
+        );
+
+        // `Self` in a method stands for the type of its impl.
+        let Item::Impl(item_impl) = &syntax_tree.items[3] else {
+            panic!("not an impl");
+        };
+        let ImplItem::Fn(method) = &item_impl.items[0] else {
+            panic!("not a method");
+        };
+        let mut names = PathNames::with_self_type(&item_impl.self_ty);
+        names.visit_impl_item_fn(method);
+        assert!(names.paths.contains(&path("Engine")));
+        assert!(!names.paths.contains(&path("Self")));
+        let context = definitions.context(&names.paths, &method.span().byte_range());
+        assert_eq!(relative(&context), ["src/engine.rs", "src/errors.rs"]);
+        assert!(context[0].excerpt().starts_with("pub struct Engine {"));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod context;
+pub mod definitions;
 pub mod rust_parser;
-pub mod structure;
\ No newline at end of file
+pub mod structure;
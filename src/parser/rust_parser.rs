@@ -1,51 +1,498 @@
-use crate::rules::generic::{RuleWithCode, extract_rule_map};
-use std::{fs, path::Path, sync::Arc};
-use syn::{File, Item, spanned::Spanned};
-
-pub fn parse_rust_file(file_path: &str) -> Vec<RuleWithCode> {
-    let content = Arc::new(fs::read_to_string(file_path).expect("Failed to read file"));
-    let file_name = Path::new(file_path)
-        .file_name()
-        .and_then(|name| name.to_str())
-        .expect("Failed to get file name");
-    let syntax_tree: File = syn::parse_file(&content).expect("Failed to parse Rust file");
+use crate::{
+    parser::{
+        context::{ContextSources, related_context},
+        definitions::{Definitions, PathNames},
+        structure::get_visibility,
+    },
+    rules::{
+        generic::{Rule, RuleWithCode, extract_rule_map},
+        scope::ProjectRule,
+        selector::ItemInfo,
+    },
+};
+use anyhow::{Context, Result};
+use quote::ToTokens;
+use std::{fs, ops::Range, path::Path, sync::Arc};
+use syn::{
+    Attribute, Block, File, ImplItem, Item, Signature, TraitItem, Visibility,
+    punctuated::Punctuated, spanned::Spanned, visit::Visit,
+};
 
+/// An item of the file, nested ones included, that rules can be checked against.
+struct ParsedItem {
+    info: ItemInfo,
+    name: String,
+    byte_range: Range<usize>,
+    sub_ranges: Vec<Range<usize>>,
+    // Paths a function refers to, resolved to the definitions shown along with it.
+    references: Vec<Vec<String>>,
+    // Header of the impl block or trait of a method.
+    enclosing: Option<String>,
+}
+
+/// Collects the rules annotating the items of a Rust file, the project rules scoped to kinds of
+/// items or to a selector, which apply to every matching item, and the other project rules,
+/// which apply to the file as a whole. The related files of project rules come from `sources`.
+pub fn parse_rust_file(
+    file_path: &str,
+    project_rules: &[ProjectRule],
+    sources: &ContextSources,
+) -> Result<Vec<RuleWithCode>> {
+    let content = Arc::new(
+        fs::read_to_string(file_path).with_context(|| format!("Failed to read {}", file_path))?,
+    );
+    parse_rust_source(file_path, content, project_rules, sources)
+}
+
+/// Collects the rules of the Rust file at `file_path`, like [`parse_rust_file`], from its
+/// `content` already read.
+pub fn parse_rust_source(
+    file_path: &str,
+    content: Arc<String>,
+    project_rules: &[ProjectRule],
+    sources: &ContextSources,
+) -> Result<Vec<RuleWithCode>> {
+    // Violations point back to the file, and fixes are written to it, so keep the whole path.
+    let file_name = file_path;
+    let syntax_tree: File =
+        syn::parse_file(&content).with_context(|| format!("Failed to parse {}", file_path))?;
+
+    let module = module_path(file_path);
     let rule_map = extract_rule_map(&content);
+    let project_rules: Vec<_> = project_rules
+        .iter()
+        .filter(|rule| rule.scope.matches_file(file_path, "rust"))
+        .collect();
+    // Code of the related files of every project rule, empty for rules without context.
+    let sources = project_rules
+        .iter()
+        .map(|rule| match rule.context.as_slice() {
+            [] => Ok(Vec::new()),
+            globs => sources.matching(globs, file_path),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut items = Vec::new();
+    collect_items(&syntax_tree.items, &module, &content, &mut items);
+    let uses: Vec<_> = syntax_tree
+        .items
+        .iter()
+        .filter(|item| matches!(item, Item::Use(_)))
+        .map(|item| content[item.span().byte_range()].to_string())
+        .collect();
+    // Built on the first item with rules, since most items have none.
+    let mut definitions = None;
     let mut rules = Vec::new();
 
-    for item in &syntax_tree.items {
-        let start_line = item.span().start().line;
-        if let Some(rule) = rule_map.get(&start_line) {
-            let (code_type, item_name) = match item {
-                Item::Enum(item_enum) => ("enum", item_enum.ident.to_string()),
-                Item::Fn(item_fn) => ("function", item_fn.sig.ident.to_string()),
-                Item::Struct(item_struct) => ("struct", item_struct.ident.to_string()),
-                _ => {
-                    eprintln!("Unsupported item type");
-                    continue;
-                }
-            };
-            let rule = RuleWithCode::new(
-                rule.to_string(),
+    for item in &items {
+        let new_rule = |rule: &Rule| {
+            RuleWithCode::new(
+                rule.clone(),
                 file_name.to_string(),
                 Arc::clone(&content),
-                code_type.to_string(),
-                item_name.clone(),
-                item.span().byte_range(),
+                item.info.kind.clone(),
+                item.name.clone(),
+                item.byte_range.clone(),
+            )
+            .with_sub_ranges(item.sub_ranges.clone())
+            .with_item_info(&item.info)
+            .with_enclosing(item.enclosing.clone())
+            .with_uses(uses.clone())
+        };
+        let start_line = content[..item.byte_range.start].matches('\n').count() + 1;
+        let annotated = rule_map.get(&start_line).map_or(&[][..], Vec::as_slice);
+        let matching: Vec<_> = project_rules
+            .iter()
+            .zip(&sources)
+            .filter(|(project_rule, _)| {
+                !project_rule.scope.is_file_level() && project_rule.scope.matches_item(&item.info)
+            })
+            .collect();
+        if annotated.is_empty() && matching.is_empty() {
+            continue;
+        }
+        // Definitions of the types and traits a function uses, which rules on it often need.
+        let types = definitions
+            .get_or_insert_with(|| Definitions::new(file_path, &content, &syntax_tree))
+            .context(&item.references, &item.byte_range);
+        for rule in annotated {
+            rules.push(new_rule(rule).with_context(types.clone()));
+        }
+        for (project_rule, sources) in matching {
+            let code = &content[item.byte_range.clone()];
+            let mut context = related_context(sources, &[&item.name], code);
+            context.extend(types.iter().cloned());
+            rules.push(
+                new_rule(&project_rule.rule)
+                    .with_context(context)
+                    .with_tools(project_rule.tools),
             );
-            rules.push(rule);
         }
     }
-    rules
+
+    let item_ranges: Vec<_> = syntax_tree
+        .items
+        .iter()
+        .map(|item| item.span().byte_range())
+        .collect();
+    let item_names: Vec<_> = items
+        .iter()
+        .filter(|item| item.info.kind != "impl")
+        .map(|item| item.name.as_str())
+        .collect();
+    for (project_rule, sources) in project_rules.iter().zip(&sources) {
+        if !project_rule.scope.is_file_level() {
+            continue;
+        }
+        let rule = RuleWithCode::new(
+            project_rule.rule.clone(),
+            file_name.to_string(),
+            Arc::clone(&content),
+            "file".to_string(),
+            Path::new(file_path)
+                .file_name()
+                .map_or(file_path.to_string(), |name| {
+                    name.to_string_lossy().into_owned()
+                }),
+            0..content.len(),
+        )
+        .with_sub_ranges(item_ranges.clone())
+        .with_item_path(&module)
+        .with_context(related_context(sources, &item_names, &content))
+        .with_tools(project_rule.tools);
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+fn join_path(module: &str, name: &str) -> String {
+    if module.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}::{}", module, name)
+    }
+}
+
+fn path_name(path: &syn::Path) -> String {
+    path.segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+/// The derived traits and the other attributes of an item, by path.
+fn attributes(attrs: &[Attribute]) -> (Vec<String>, Vec<String>) {
+    let mut derives = Vec::new();
+    let mut others = Vec::new();
+    for attr in attrs {
+        if attr.path().is_ident("derive") {
+            if let Ok(paths) =
+                attr.parse_args_with(Punctuated::<syn::Path, syn::Token![,]>::parse_terminated)
+            {
+                derives.extend(paths.iter().map(path_name));
+            }
+        } else if !attr.path().is_ident("doc") {
+            others.push(path_name(attr.path()));
+        }
+    }
+    (derives, others)
+}
+
+fn item_info(kind: &str, path: String, vis: &Visibility, attrs: &[Attribute]) -> ItemInfo {
+    let (derives, attributes) = attributes(attrs);
+    ItemInfo {
+        kind: kind.to_string(),
+        path,
+        visibility: get_visibility(vis),
+        derives,
+        attributes,
+        ..Default::default()
+    }
+}
+
+/// Walks `items` and the modules, impls and traits among them, collecting the supported items
+/// with their path under `module`.
+fn collect_items(items: &[Item], module: &str, content: &str, out: &mut Vec<ParsedItem>) {
+    for item in items {
+        let (kind, name, vis, attrs) = match item {
+            Item::Enum(i) => ("enum", i.ident.to_string(), &i.vis, &i.attrs),
+            Item::Fn(i) => ("function", i.sig.ident.to_string(), &i.vis, &i.attrs),
+            Item::Struct(i) => ("struct", i.ident.to_string(), &i.vis, &i.attrs),
+            Item::Mod(i) => ("mod", i.ident.to_string(), &i.vis, &i.attrs),
+            Item::Trait(i) => ("trait", i.ident.to_string(), &i.vis, &i.attrs),
+            Item::Impl(i) => (
+                "impl",
+                i.self_ty.to_token_stream().to_string(),
+                &Visibility::Inherited,
+                &i.attrs,
+            ),
+            _ => continue,
+        };
+        let path = join_path(module, &name);
+        let mut info = item_info(kind, path.clone(), vis, attrs);
+        let mut references = PathNames::default();
+        match item {
+            Item::Fn(item_fn) => {
+                info.is_async = item_fn.sig.asyncness.is_some();
+                references.visit_item_fn(item_fn);
+            }
+            Item::Impl(item_impl) => {
+                info.trait_name = item_impl
+                    .trait_
+                    .as_ref()
+                    .map(|(_, path, _)| path_name(path))
+            }
+            _ => {}
+        }
+        out.push(ParsedItem {
+            info,
+            name,
+            byte_range: item.span().byte_range(),
+            sub_ranges: sub_item_ranges(item),
+            references: references.paths,
+            enclosing: None,
+        });
+        match item {
+            Item::Mod(item_mod) => {
+                if let Some((_, nested)) = &item_mod.content {
+                    collect_items(nested, &path, content, out);
+                }
+            }
+            Item::Impl(item_impl) => {
+                let enclosing = header(content, item, &item_impl.attrs, &item_impl.brace_token);
+                for impl_item in &item_impl.items {
+                    if let ImplItem::Fn(method) = impl_item {
+                        let info = item_info("function", String::new(), &method.vis, &method.attrs);
+                        out.push(function(
+                            info,
+                            &method.sig,
+                            &path,
+                            impl_item.span().byte_range(),
+                            &method.block,
+                            &enclosing,
+                            PathNames::with_self_type(&item_impl.self_ty),
+                        ));
+                    }
+                }
+            }
+            Item::Trait(item_trait) => {
+                let enclosing = header(content, item, &item_trait.attrs, &item_trait.brace_token);
+                for trait_item in &item_trait.items {
+                    if let TraitItem::Fn(method) = trait_item
+                        && let Some(block) = &method.default
+                    {
+                        // Trait methods are as visible as their trait.
+                        let info =
+                            item_info("function", String::new(), &item_trait.vis, &method.attrs);
+                        out.push(function(
+                            info,
+                            &method.sig,
+                            &path,
+                            trait_item.span().byte_range(),
+                            block,
+                            &enclosing,
+                            PathNames::default(),
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A method of an impl or trait, which rules see as a function. `references` collects the
+/// paths it refers to.
+fn function(
+    mut info: ItemInfo,
+    sig: &Signature,
+    parent: &str,
+    byte_range: Range<usize>,
+    block: &Block,
+    enclosing: &str,
+    mut references: PathNames,
+) -> ParsedItem {
+    let name = sig.ident.to_string();
+    info.path = join_path(parent, &name);
+    info.is_async = sig.asyncness.is_some();
+    references.visit_signature(sig);
+    references.visit_block(block);
+    ParsedItem {
+        info,
+        name,
+        byte_range,
+        sub_ranges: block.stmts.iter().map(|s| s.span().byte_range()).collect(),
+        references: references.paths,
+        enclosing: Some(enclosing.to_string()),
+    }
+}
+
+/// Declaration of an impl block or trait, such as `impl Display for Rule`, without its
+/// attributes and body.
+fn header(content: &str, item: &Item, attrs: &[Attribute], brace: &syn::token::Brace) -> String {
+    let start = attrs.last().map_or(item.span().byte_range().start, |attr| {
+        attr.span().byte_range().end
+    });
+    let end = brace.span.open().byte_range().start;
+    content[start..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Module path of a file relative to its crate's `src` directory, such as `cakes` for
+/// `src/cakes/mod.rs`. Files outside of a `src` directory are named by their stem.
+fn module_path(file_path: &str) -> String {
+    let path = Path::new(file_path).with_extension("");
+    let components: Vec<_> = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let relative = match components.iter().rposition(|c| c == "src") {
+        Some(idx) => &components[idx + 1..],
+        None => &components[components.len().saturating_sub(1)..],
+    };
+    let mut segments = relative.to_vec();
+    if matches!(
+        segments.last().map(String::as_str),
+        Some("mod" | "lib" | "main")
+    ) {
+        segments.pop();
+    }
+    segments.join("::")
+}
+
+/// Byte ranges of the direct children of an item, i.e. the points where it can be split.
+fn sub_item_ranges(item: &Item) -> Vec<Range<usize>> {
+    match item {
+        Item::Mod(item_mod) => item_mod
+            .content
+            .iter()
+            .flat_map(|(_, items)| items.iter().map(|i| i.span().byte_range()))
+            .collect(),
+        Item::Impl(item_impl) => item_impl
+            .items
+            .iter()
+            .map(|i| i.span().byte_range())
+            .collect(),
+        Item::Trait(item_trait) => item_trait
+            .items
+            .iter()
+            .map(|i| i.span().byte_range())
+            .collect(),
+        Item::Enum(item_enum) => item_enum
+            .variants
+            .iter()
+            .map(|v| v.span().byte_range())
+            .collect(),
+        Item::Struct(item_struct) => item_struct
+            .fields
+            .iter()
+            .map(|f| f.span().byte_range())
+            .collect(),
+        Item::Fn(item_fn) => item_fn
+            .block
+            .stmts
+            .iter()
+            .map(|s| s.span().byte_range())
+            .collect(),
+        _ => Vec::new(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{config::rule_config::RuleConfig, rules::scope::ProjectRule};
 
     #[test]
     fn test_parse_rust_file() {
-        let rules = parse_rust_file("./lint-examples/rust_enum.rs");
+        let rules = parse_rust_file(
+            "./lint-examples/rust_enum.rs",
+            &[],
+            &ContextSources::default(),
+        )
+        .unwrap();
         assert!(!rules.is_empty());
+
+        let project_rules = [ProjectRule::new(
+            Rule::new("No blocking IO in async code."),
+            ".melangerules:1".to_string(),
+        )];
+        let with_project = parse_rust_file(
+            "./lint-examples/rust_enum.rs",
+            &project_rules,
+            &ContextSources::default(),
+        )
+        .unwrap();
+        assert_eq!(with_project.len(), rules.len() + 1);
+        assert_eq!(
+            RuleWithCode::batch(rules.clone(), |_| false).len(),
+            rules.len()
+        );
+        assert!(rules[0].item_path().starts_with("rust_enum::"));
+
+        assert_eq!(module_path("src/cakes/mod.rs"), "cakes");
+        assert_eq!(
+            module_path("./src/engine/llm_engine.rs"),
+            "engine::llm_engine"
+        );
+        assert_eq!(module_path("src/main.rs"), "");
+    }
+
+    #[test]
+    fn test_scoped_project_rules() {
+        let config: RuleConfig = toml::from_str(
+            r#"
+            description = "struct fields should be private"
+            paths = ["lint-examples/*.rs"]
+            item_kinds = ["struct", "fn"]
+            "#,
+        )
+        .unwrap();
+        let rules = parse_rust_file(
+            "./lint-examples/rust_enum.rs",
+            &[config.project_rule("melange-config.toml", 0)],
+            &ContextSources::default(),
+        )
+        .unwrap()
+        .into_iter()
+        .filter(|rule| rule.rules()[0].description() == config.description)
+        .collect::<Vec<_>>();
+        let paths: Vec<_> = rules.iter().map(|rule| rule.item_path()).collect();
+        assert!(paths.contains(&"rust_enum::cakes::MyCake"));
+        assert!(paths.contains(&"rust_enum::cakes::is_empty_or_zero"));
+        assert!(rules.iter().all(|rule| rule.item_kind() != "enum"));
+
+        let config: RuleConfig = toml::from_str(
+            r#"
+            description = "public async functions should document their errors"
+            selector = "pub async fn in engine::*"
+            "#,
+        )
+        .unwrap();
+        let rules = parse_rust_file(
+            "src/engine/llm_engine.rs",
+            &[config.project_rule("melange-config.toml", 0)],
+            &ContextSources::default(),
+        )
+        .unwrap();
+        let paths: Vec<_> = rules
+            .iter()
+            .filter(|rule| rule.rules()[0].description() == config.description)
+            .map(|rule| rule.item_path())
+            .collect();
+        assert!(paths.contains(&"engine::llm_engine::LlmEngine::query"));
+        assert!(!paths.contains(&"engine::llm_engine::LlmEngine::new"));
+
+        let query = rules
+            .iter()
+            .find(|rule| rule.item_path() == "engine::llm_engine::LlmEngine::query")
+            .unwrap()
+            .to_prompt();
+        assert!(query.contains("kind: async function\nname: query\n"));
+        assert!(query.contains("visibility: pub\nin: impl LlmEngine\n"));
+        let uses = &query[query.find("<uses-").unwrap()..];
+        assert!(uses.contains(">\nuse anyhow::Result;"));
     }
 }
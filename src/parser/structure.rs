@@ -13,6 +13,33 @@ pub struct ModuleNode {
     path: String,
 }
 
+impl ModuleNode {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn visibility(&self) -> &str {
+        &self.visibility
+    }
+
+    pub fn is_async(&self) -> bool {
+        self.is_async
+    }
+
+    pub fn children(&self) -> &[ModuleNode] {
+        &self.children
+    }
+
+    /// The file the node is defined in.
+    pub fn file(&self) -> &str {
+        &self.path
+    }
+}
+
 fn get_crate_name(crate_path: &Path) -> String {
     let cargo_path = crate_path.join("Cargo.toml");
 
@@ -114,9 +141,12 @@ fn process_file(file_path: &Path, parent: &mut ModuleNode) {
                     // Check for [module_name]/mod.rs
                     let mod_dir_file = parent_dir.join(&mod_name).join("mod.rs");
 
+                    // The module and its items live in the module's own file
                     if mod_file.exists() {
+                        module.path = mod_file.to_string_lossy().into_owned();
                         process_file(&mod_file, &mut module);
                     } else if mod_dir_file.exists() {
+                        module.path = mod_dir_file.to_string_lossy().into_owned();
                         process_file(&mod_dir_file, &mut module);
                     }
                 }
@@ -217,7 +247,7 @@ fn get_self_type(ty: &syn::Type) -> String {
         _ => String::new(),
     }
 }
-fn get_visibility(vis: &Visibility) -> String {
+pub(crate) fn get_visibility(vis: &Visibility) -> String {
     match vis {
         Visibility::Public(_) => "pub".to_string(),
         Visibility::Restricted(restricted) => {
@@ -0,0 +1,192 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::ErrorKind,
+    path::Path,
+};
+
+use crate::rules::{generic::normalize_path, violation::Violation};
+
+pub const BASELINE_FILE: &str = ".melange/baseline.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BaselineEntry {
+    pub fingerprint: String,
+    pub rule: String,
+    /// Empty in baselines recorded before rule ids were stored, whose entries are never pruned.
+    #[serde(default)]
+    pub rule_id: String,
+    pub file_name: String,
+    pub item: String,
+    pub quote: String,
+}
+
+/// Violations accepted when melange was adopted, which later runs do not report.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Baseline {
+    entries: Vec<BaselineEntry>,
+}
+
+impl Baseline {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn from_violations(violations: &[Violation]) -> Self {
+        let entries = violations
+            .iter()
+            .map(|v| BaselineEntry {
+                fingerprint: v.fingerprint.clone(),
+                rule: v.rule.clone(),
+                rule_id: v.rule_id.clone(),
+                file_name: normalize_path(&v.file_name).to_string(),
+                item: format!("{} {}", v.item_kind, v.item_path),
+                quote: v.quote.clone(),
+            })
+            .collect();
+        Self { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn counts(&self) -> HashMap<&str, usize> {
+        let mut counts = HashMap::new();
+        for entry in &self.entries {
+            *counts.entry(entry.fingerprint.as_str()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Drops the violations recorded in the baseline, returning the new ones and the number of
+    /// dropped ones. A fingerprint recorded n times hides at most n violations.
+    pub fn filter(&self, violations: Vec<Violation>) -> (Vec<Violation>, usize) {
+        let mut counts = self.counts();
+        let mut baselined = 0;
+        let new = violations
+            .into_iter()
+            .filter(|v| match counts.get_mut(v.fingerprint.as_str()) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    baselined += 1;
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        (new, baselined)
+    }
+
+    /// Removes the entries that no longer match a violation, returning how many were removed.
+    /// Only the entries of the `evaluated` pairs of file and rule id are considered, since the
+    /// others may still be there.
+    pub fn prune(
+        &mut self,
+        violations: &[Violation],
+        evaluated: &HashSet<(String, String)>,
+    ) -> usize {
+        let mut current: HashMap<&str, usize> = HashMap::new();
+        for violation in violations {
+            *current.entry(violation.fingerprint.as_str()).or_default() += 1;
+        }
+        let before = self.entries.len();
+        self.entries.retain(|entry| {
+            let key = (
+                normalize_path(&entry.file_name).to_string(),
+                entry.rule_id.clone(),
+            );
+            if !evaluated.contains(&key) {
+                return true;
+            }
+            match current.get_mut(entry.fingerprint.as_str()) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    true
+                }
+                _ => false,
+            }
+        });
+        before - self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violation(rule_id: &str, file_name: &str, line: usize, quote: &str) -> Violation {
+        let content = format!("{}{}", "\n".repeat(line - 1), quote);
+        Violation::test(rule_id, file_name, &content, quote)
+    }
+
+    fn evaluated(pairs: &[(&str, &str)]) -> HashSet<(String, String)> {
+        pairs
+            .iter()
+            .map(|(file, rule_id)| (file.to_string(), rule_id.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_and_prune() {
+        let mut baseline = Baseline::from_violations(&[
+            violation("doc", "./a.rs", 1, "fn add(a: i32)"),
+            violation("doc", "b.rs", 1, "fn add(a: i32)"),
+        ]);
+        let (new, baselined) = baseline.filter(vec![
+            violation("doc", "a.rs", 10, "fn  add(a: i32)"),
+            violation("doc", "a.rs", 12, "fn add(a: i32)"),
+        ]);
+        assert_eq!(baselined, 1);
+        assert_eq!(new.len(), 1);
+
+        let removed = baseline.prune(&[], &evaluated(&[("a.rs", "doc")]));
+        assert_eq!(removed, 1);
+        assert_eq!(baseline.entries[0].file_name, "b.rs");
+    }
+
+    #[test]
+    fn test_prune_keeps_unevaluated_rules() {
+        let mut baseline = Baseline::from_violations(&[
+            violation("doc", "a.rs", 1, "fn add(a: i32)"),
+            violation("no-unwrap", "a.rs", 2, "x.unwrap()"),
+            violation("no-unwrap", "./b.rs", 2, "x.unwrap()"),
+        ]);
+        // Only `doc` was checked on a.rs, e.g. because the budget ran out before `no-unwrap`.
+        let removed = baseline.prune(&[], &evaluated(&[("a.rs", "doc"), ("b.rs", "doc")]));
+        assert_eq!(removed, 1);
+        let kept: Vec<_> = baseline
+            .entries
+            .iter()
+            .map(|entry| (entry.file_name.as_str(), entry.rule_id.as_str()))
+            .collect();
+        assert_eq!(kept, [("a.rs", "no-unwrap"), ("b.rs", "no-unwrap")]);
+
+        let still_there = violation("no-unwrap", "./a.rs", 7, "x.unwrap()");
+        let removed = baseline.prune(
+            &[still_there],
+            &evaluated(&[("a.rs", "no-unwrap"), ("b.rs", "no-unwrap")]),
+        );
+        assert_eq!(removed, 1);
+        assert_eq!(baseline.entries[0].file_name, "a.rs");
+    }
+}
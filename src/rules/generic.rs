@@ -1,18 +1,83 @@
-use anyhow::Result;
 use std::{
+    borrow::Cow,
     collections::HashMap,
     fmt::Display,
     ops::Range,
+    path::Path,
     sync::{Arc, LazyLock},
 };
 
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-static AIRULE: LazyLock<Regex> = LazyLock::new(|| Regex::new("// +#AIRULE: +(.+)").unwrap());
+use crate::{
+    engine::{cache::stable_hash, prompt::PromptTemplates},
+    rules::{selector::ItemInfo, suppression::is_suppression},
+};
 
-#[derive(Debug, Clone)]
+static AIRULE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"// +#AIRULE(?:\(([^)\s]+)\))?: +(.+)").unwrap());
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    #[default]
+    Warning,
+    Info,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Info => write!(f, "info"),
+        }
+    }
+}
+
+/// A rule, identified by an explicit id or by an id derived from its text.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Rule {
+    id: String,
     description: String,
+    severity: Severity,
+}
+
+impl Rule {
+    pub fn new(description: impl Into<String>) -> Self {
+        let description = description.into();
+        Self {
+            id: derived_id(&description),
+            description,
+            severity: Severity::default(),
+        }
+    }
+
+    pub fn with_id(id: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            description: description.into(),
+            severity: Severity::default(),
+        }
+    }
+
+    pub fn with_severity(self, severity: Severity) -> Self {
+        Self { severity, ..self }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
 }
 
 impl Display for Rule {
@@ -20,20 +85,70 @@ impl Display for Rule {
         write!(f, "{}", self.description)
     }
 }
-#[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, Serialize)]
 struct RuleMetaData {
+    #[serde(rename = "kind")]
     code_type: String, // "enum", "function", "struct", etc.
+    #[serde(rename = "name")]
     item_name: String, // Name of the item
+    #[serde(rename = "path")]
+    item_path: String, // Module path of the item, such as `cakes::MyCake`
+    visibility: String, // `pub`, `pub(crate)`... or `private`; empty when unknown
+    is_async: bool,     // Whether the function is async
+    attributes: Vec<String>, // Such as `derive(Debug, Clone)` or `test`
+    enclosing: Option<String>, // Header of the impl or trait of a method
+    #[serde(skip)]
+    uses: Vec<String>, // `use` declarations at the top of the file
+}
+
+/// Code of a related file shown along with the checked code: the whole file, or the items
+/// relevant to the check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextFile {
+    pub path: String,
+    pub content: Arc<String>,
+    pub ranges: Vec<Range<usize>>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
+impl ContextFile {
+    pub fn whole(path: String, content: Arc<String>) -> Self {
+        let ranges = std::iter::once(0..content.len()).collect();
+        Self {
+            path,
+            content,
+            ranges,
+        }
+    }
+
+    /// The included code, with elisions between non-contiguous ranges.
+    pub fn excerpt(&self) -> String {
+        self.ranges
+            .iter()
+            .map(|range| &self.content[range.clone()])
+            .collect::<Vec<_>>()
+            .join("\n...\n")
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct RuleWithCode {
-    rule: String,
+    // Several rules when batched; they are numbered from 1 in the prompt.
+    rules: Vec<Rule>,
     file_name: String,
     file_content: Arc<String>,
     byte_range: Range<usize>,
+    // Range of the whole item, which stays put when `byte_range` is narrowed to a chunk.
+    item_range: Range<usize>,
+    // Byte ranges of the item's direct children (module items, fields, statements...),
+    // used as split points when the item is too large for a single prompt.
+    sub_ranges: Vec<Range<usize>>,
+    // Range of the item's header, such as its signature or `impl` line, shown ahead of a chunk
+    // so that the model knows what the chunk is part of.
+    header: Option<Range<usize>>,
+    // Code from other files the rules refer to.
+    context: Vec<ContextFile>,
+    // Whether the model can explore the crate with tool calls before giving its verdict.
+    tools: bool,
     meta: RuleMetaData,
 }
 
@@ -41,11 +156,100 @@ impl RuleWithCode {
     pub fn get_code_block(&self) -> &str {
         &self.file_content[self.byte_range.clone()]
     }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn file_content(&self) -> &str {
+        &self.file_content
+    }
+
+    pub fn file_content_arc(&self) -> &Arc<String> {
+        &self.file_content
+    }
+
+    pub fn context(&self) -> &[ContextFile] {
+        &self.context
+    }
+
+    pub fn uses_tools(&self) -> bool {
+        self.tools
+    }
+
+    pub fn item_kind(&self) -> &str {
+        &self.meta.code_type
+    }
+
+    pub fn item_name(&self) -> &str {
+        &self.meta.item_name
+    }
+
+    pub fn item_path(&self) -> &str {
+        &self.meta.item_path
+    }
+
+    /// Language of the file, from its extension.
+    pub fn language(&self) -> &str {
+        match Path::new(&self.file_name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some("rs") => "rust",
+            Some("py") => "python",
+            Some(ext) => ext,
+            None => "",
+        }
+    }
+
+    pub fn byte_range(&self) -> &Range<usize> {
+        &self.byte_range
+    }
+
+    pub fn item_range(&self) -> &Range<usize> {
+        &self.item_range
+    }
+
+    /// Range of the header of the item a chunk belongs to, `None` for whole items.
+    pub fn header_range(&self) -> Option<&Range<usize>> {
+        self.header.as_ref()
+    }
+
+    /// The code sent to the model: the code block, after the header of its item for a chunk.
+    pub fn prompt_code(&self) -> Cow<'_, str> {
+        let code = self.get_code_block();
+        let Some(header) = &self.header else {
+            return Cow::Borrowed(code);
+        };
+        let start = self.byte_range.start;
+        let line_start = self.file_content[..start]
+            .rfind('\n')
+            .map_or(0, |idx| idx + 1);
+        let indent = &self.file_content[line_start..start];
+        let indent = if indent.trim().is_empty() { indent } else { "" };
+        let mut prompt_code = format!("{}\n", &self.file_content[header.clone()]);
+        // Sub-items left out between the header and the chunk are marked as such.
+        if !self.file_content[header.end..start].trim().is_empty() {
+            prompt_code.push_str(&format!("{}// ...\n", indent));
+        }
+        prompt_code.push_str(indent);
+        prompt_code.push_str(code);
+        Cow::Owned(prompt_code)
+    }
+
+    /// 1-based line number of a byte offset in the file.
+    pub fn line_at(&self, byte_offset: usize) -> usize {
+        line_at(&self.file_content, byte_offset)
+    }
 }
 
 impl RuleWithCode {
     pub fn new(
-        rule: String,
+        rule: Rule,
         file_name: String,
         file_content: Arc<String>,
         code_type: String,
@@ -54,50 +258,372 @@ impl RuleWithCode {
     ) -> Self {
         let meta = RuleMetaData {
             code_type,
+            item_path: item_name.clone(),
             item_name,
+            ..Default::default()
         };
         Self {
-            rule,
+            rules: vec![rule],
             file_name,
             file_content,
+            item_range: byte_range.clone(),
             byte_range,
+            sub_ranges: Vec::new(),
+            header: None,
+            context: Vec::new(),
+            tools: false,
             meta,
         }
     }
 
-    pub fn to_prompt(&self) -> String {
-        format!(
-            r#"
-        <rule>{}<rule>
-        <path>{}<path>
-        <code>{}<code>
-        "#,
-            self.rule,
-            self.file_name,
-            self.get_code_block()
+    /// A standalone snippet of code checked against `rule`, such as a rule example.
+    pub fn snippet(rule: Rule, name: String, code: &str) -> Self {
+        Self::new(
+            rule,
+            "example.rs".to_string(),
+            Arc::new(code.to_string()),
+            "example".to_string(),
+            name,
+            0..code.len(),
         )
     }
-}
-pub fn extract_rule_map(content: &str) -> HashMap<usize, Rule> {
-    content
-        .lines()
-        .enumerate()
-        .filter_map(|(i, line)| {
-            if let Some(caps) = AIRULE.captures(line) {
-                let rule = Rule {
-                    description: caps[1].to_string(),
-                };
-                Some((i + 2, rule))
-            } else {
-                None
+
+    pub fn with_sub_ranges(mut self, sub_ranges: Vec<Range<usize>>) -> Self {
+        self.sub_ranges = sub_ranges;
+        self
+    }
+
+    /// The rule over another version of its file, such as the file with its secrets redacted.
+    /// The ranges of the item are kept, so `file_content` must have the same layout.
+    pub fn with_file_content(mut self, file_content: Arc<String>) -> Self {
+        self.file_content = file_content;
+        self
+    }
+
+    pub fn with_context(mut self, context: Vec<ContextFile>) -> Self {
+        self.context = context;
+        self
+    }
+
+    pub fn with_tools(mut self, tools: bool) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn with_item_path(mut self, item_path: impl Into<String>) -> Self {
+        self.meta.item_path = item_path.into();
+        self
+    }
+
+    /// Describes the item to the model with its path, visibility and attributes.
+    pub fn with_item_info(mut self, info: &ItemInfo) -> Self {
+        self.meta.item_path = info.path.clone();
+        self.meta.visibility = info.visibility.clone();
+        self.meta.is_async = info.is_async;
+        self.meta.attributes = match info.derives.as_slice() {
+            [] => Vec::new(),
+            derives => vec![format!("derive({})", derives.join(", "))],
+        };
+        self.meta.attributes.extend(info.attributes.iter().cloned());
+        self
+    }
+
+    /// Header of the impl block or trait a method belongs to.
+    pub fn with_enclosing(mut self, enclosing: Option<String>) -> Self {
+        self.meta.enclosing = enclosing;
+        self
+    }
+
+    /// The `use` declarations of the file, which tell where the names in the code come from.
+    pub fn with_uses(mut self, uses: Vec<String>) -> Self {
+        self.meta.uses = uses;
+        self
+    }
+
+    /// Rewrites the metadata copied out of the source of the file: the `use` declarations, the
+    /// header of the enclosing impl or trait and the attributes.
+    pub fn map_source_metadata(mut self, f: impl Fn(&str) -> String) -> Self {
+        self.meta.uses = self.meta.uses.iter().map(|u| f(u)).collect();
+        self.meta.enclosing = self.meta.enclosing.as_deref().map(&f);
+        self.meta.attributes = self.meta.attributes.iter().map(|a| f(a)).collect();
+        self
+    }
+
+    fn with_byte_range(&self, byte_range: Range<usize>) -> Self {
+        Self {
+            byte_range,
+            sub_ranges: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Splits the item along its sub-item boundaries so that the prompt of every chunk fits in
+    /// `max_tokens`, as measured by `measure`. Items without sub-items are returned whole, and
+    /// sub-items are not split further: one too large on its own makes a chunk over the budget.
+    pub fn split(
+        &self,
+        max_tokens: usize,
+        measure: impl Fn(&RuleWithCode) -> usize,
+    ) -> Vec<RuleWithCode> {
+        if measure(self) <= max_tokens || self.sub_ranges.is_empty() {
+            return vec![self.clone()];
+        }
+        let header = self.header_before(self.sub_ranges[0].start);
+        let chunk = |range: Range<usize>| Self {
+            header: header.clone(),
+            ..self.with_byte_range(range)
+        };
+        let mut chunks = Vec::new();
+        let mut current: Option<Range<usize>> = None;
+        for range in &self.sub_ranges {
+            current = match current {
+                Some(current) if measure(&chunk(current.start..range.end)) <= max_tokens => {
+                    Some(current.start..range.end)
+                }
+                Some(current) => {
+                    chunks.push(chunk(current));
+                    Some(range.clone())
+                }
+                None => Some(range.clone()),
+            };
+        }
+        if let Some(current) = current {
+            chunks.push(chunk(current));
+        }
+        chunks
+    }
+
+    /// The range of the item's code before its first sub-item, which starts at `end`, past the
+    /// doc comments and attributes the item metadata already carries.
+    fn header_before(&self, end: usize) -> Option<Range<usize>> {
+        let mut start = self.item_range.start;
+        for line in self.file_content[start..end].split_inclusive('\n') {
+            let trimmed = line.trim();
+            if !(trimmed.starts_with("//") || trimmed.starts_with("#[") && trimmed.ends_with(']')) {
+                break;
             }
-        })
-        .collect::<HashMap<usize, Rule>>()
+            start += line.len();
+        }
+        let header = self.file_content[start..end].trim();
+        let start = start + self.file_content[start..end].find(header)?;
+        (!header.is_empty()).then(|| start..start + header.len())
+    }
+
+    /// Groups the rules that apply to the same code block of the same file, so that the block
+    /// is sent once with all of its rules. The rules `apart` picks, such as those with prompt
+    /// templates of their own, are checked on their own.
+    pub fn batch(rules: Vec<RuleWithCode>, apart: impl Fn(&Rule) -> bool) -> Vec<RuleWithCode> {
+        let mut batches: Vec<RuleWithCode> = Vec::new();
+        for rule in rules {
+            if rule.rules.iter().any(&apart) {
+                batches.push(rule);
+                continue;
+            }
+            match batches.iter_mut().find(|b| {
+                !b.rules.iter().any(&apart)
+                    && b.file_name == rule.file_name
+                    && b.byte_range == rule.byte_range
+                    && Arc::ptr_eq(&b.file_content, &rule.file_content)
+                    && b.context == rule.context
+                    && b.tools == rule.tools
+            }) {
+                Some(batch) => batch.rules.extend(rule.rules),
+                None => batches.push(rule),
+            }
+        }
+        batches
+    }
+
+    /// The prompt of the check with the built-in template. The configured templates are
+    /// applied by [`LlmConfig::prompt`](crate::engine::llm_engine::LlmConfig::prompt).
+    pub fn to_prompt(&self) -> String {
+        PromptTemplates::default_prompt(self)
+    }
+
+    /// The variables prompt templates are rendered with.
+    pub fn prompt_vars<'a>(&'a self, nonce: &'a str) -> PromptVars<'a> {
+        PromptVars {
+            nonce,
+            rule: &self.rules[0],
+            rules: &self.rules,
+            code: self.prompt_code(),
+            path: &self.file_name,
+            language: self.language(),
+            item: &self.meta,
+            uses: &self.meta.uses,
+            context: self
+                .context
+                .iter()
+                .map(|file| ContextVars {
+                    path: &file.path,
+                    code: file.excerpt(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What prompt templates see of a rule check.
+#[derive(Serialize)]
+pub struct PromptVars<'a> {
+    /// Random delimiter of the sections of the prompt.
+    nonce: &'a str,
+    /// The first rule of the check, the only one unless rules are batched.
+    rule: &'a Rule,
+    rules: &'a [Rule],
+    code: Cow<'a, str>,
+    path: &'a str,
+    language: &'a str,
+    item: &'a RuleMetaData,
+    uses: &'a [String],
+    context: Vec<ContextVars<'a>>,
+}
+
+#[derive(Serialize)]
+struct ContextVars<'a> {
+    path: &'a str,
+    code: String,
+}
+
+/// 1-based line number of a byte offset in `content`.
+pub fn line_at(content: &str, byte_offset: usize) -> usize {
+    content[..byte_offset.min(content.len())]
+        .matches('\n')
+        .count()
+        + 1
+}
+
+/// A path as given on the command line, without its leading `./`, so that `--dir .` and
+/// `-f src/a.rs` name a file the same way.
+pub fn normalize_path(path: &str) -> &str {
+    path.trim_start_matches("./")
+}
+
+/// Collapses whitespace and case, so that rewrapping a rule does not change its id.
+pub(crate) fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn derived_id(description: &str) -> String {
+    stable_hash(&[&normalize(description)])[..8].to_string()
+}
+
+pub(crate) fn is_rule_comment(line: &str) -> bool {
+    AIRULE.is_match(line)
 }
 
-pub fn load_project_rules() -> Result<Vec<Rule>> {
-    Ok(vec![Rule {
-        description: "For Rust Code, don't enforce safety checks".to_string(),
-    }])
+/// Maps the line of every annotated item to its rules. Consecutive `#AIRULE` comments all
+/// apply to the item that follows them, past any `melange-allow` comments. A rule is given an
+/// explicit id with `#AIRULE(<id>): <rule>`.
+pub fn extract_rule_map(content: &str) -> HashMap<usize, Vec<Rule>> {
+    let mut rule_map = HashMap::new();
+    let mut pending = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if let Some(caps) = AIRULE.captures(line) {
+            pending.push(match caps.get(1) {
+                Some(id) => Rule::with_id(id.as_str(), &caps[2]),
+                None => Rule::new(&caps[2]),
+            });
+        } else if !pending.is_empty() && !is_suppression(line) {
+            rule_map.insert(i + 1, std::mem::take(&mut pending));
+        }
+    }
+    rule_map
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::violation::parse_response;
+
+    #[test]
+    fn test_rule_ids() {
+        let content = "// #AIRULE(no-unwrap): no unwrap\n// #AIRULE: No  Panics\nfn a() {}\n";
+        let rules = &extract_rule_map(content)[&3];
+        assert_eq!(rules[0].id(), "no-unwrap");
+        assert_eq!(rules[1].id(), Rule::new("no panics").id());
+        assert_eq!(rules[1].to_string(), "No  Panics");
+    }
+
+    #[test]
+    fn test_batch() {
+        let content = Arc::new("fn first(v: &[i32]) -> i32 { v[0] }".to_string());
+        let check = |rule: Rule| {
+            RuleWithCode::new(
+                rule,
+                "a.rs".to_string(),
+                Arc::clone(&content),
+                "function".to_string(),
+                "first".to_string(),
+                0..content.len(),
+            )
+        };
+        let rules = vec![
+            check(Rule::with_id("no-panics", "no panics")),
+            check(Rule::with_id("no-index", "no indexing")),
+            check(Rule::with_id("documented", "document the function")),
+        ];
+        let batches = RuleWithCode::batch(rules.clone(), |_| false);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].rules().len(), 3);
+
+        // A rule with a template of its own is checked on its own.
+        let batches = RuleWithCode::batch(rules, |rule| rule.id() == "no-index");
+        let ids: Vec<Vec<_>> = batches
+            .iter()
+            .map(|batch| batch.rules().iter().map(Rule::id).collect())
+            .collect();
+        assert_eq!(ids, [vec!["no-panics", "documented"], vec!["no-index"]]);
+    }
+
+    #[test]
+    fn test_split_along_sub_ranges() {
+        let content = "/// Functions.\n#[cfg(test)]\nmod a {\n    fn one() {}\n    fn two() {}\n    fn three() {}\n}\n";
+        let sub_ranges = ["fn one() {}", "fn two() {}", "fn three() {}"]
+            .iter()
+            .map(|f| {
+                let start = content.find(f).unwrap();
+                start..start + f.len()
+            })
+            .collect();
+        let rule = RuleWithCode::new(
+            Rule::new("no functions"),
+            "a.rs".to_string(),
+            Arc::new(content.to_string()),
+            "mod".to_string(),
+            "a".to_string(),
+            0..content.len() - 1,
+        )
+        .with_sub_ranges(sub_ranges);
+
+        let measure = |chunk: &RuleWithCode| chunk.prompt_code().len();
+        let whole = rule.split(usize::MAX, measure);
+        assert_eq!(whole.len(), 1);
+        assert_eq!(whole[0].prompt_code(), &content[..content.len() - 1]);
+
+        // Every chunk follows the header of the module, with its doc comment and attributes left
+        // out, and with an elision when it does not come first.
+        let expected = [
+            "mod a {\n    fn one() {}",
+            "mod a {\n    // ...\n    fn two() {}",
+            "mod a {\n    // ...\n    fn three() {}",
+        ];
+        let chunks = rule.split(expected[2].len(), measure);
+        let codes: Vec<_> = chunks.iter().map(|c| c.prompt_code()).collect();
+        assert_eq!(codes, expected);
+        let blocks: Vec<_> = chunks.iter().map(|c| c.get_code_block()).collect();
+        assert_eq!(blocks, vec!["fn one() {}", "fn two() {}", "fn three() {}"]);
+        assert_eq!(chunks[1].line_at(chunks[1].byte_range().start), 5);
+        // Sub-items too large on their own are not split further.
+        assert_eq!(rule.split(1, measure).len(), 3);
+
+        // Quotes of the header are located in the file.
+        let response = r#"{"violations": [{"quote": "mod a", "explanation": "a module"}]}"#;
+        let violations = parse_response(response, &chunks[1]).unwrap();
+        assert_eq!(violations[0].byte_offset, content.find("mod a").unwrap());
+        assert_eq!(violations[0].line, 3);
+    }
+}
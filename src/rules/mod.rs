@@ -1 +1,6 @@
-pub mod generic;
\ No newline at end of file
+pub mod baseline;
+pub mod generic;
+pub mod scope;
+pub mod selector;
+pub mod suppression;
+pub mod violation;
@@ -0,0 +1,185 @@
+use anyhow::{Result, bail};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::rules::{
+    generic::Rule,
+    selector::{ItemInfo, Selector},
+};
+
+/// Kinds of the items the parser collects.
+pub const ITEM_KINDS: [&str; 6] = ["function", "struct", "enum", "mod", "trait", "impl"];
+
+/// The kind the parser gives to items of the kind named `name` in a config, which accepts
+/// `fn` and `module` as aliases.
+pub fn item_kind(name: &str) -> &str {
+    match name {
+        "fn" => "function",
+        "module" => "mod",
+        kind => kind,
+    }
+}
+
+#[derive(Deserialize)]
+struct RawScope {
+    #[serde(default)]
+    paths: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    languages: Vec<String>,
+    #[serde(default)]
+    item_kinds: Vec<String>,
+    selector: Option<String>,
+}
+
+/// The files and items a project rule applies to. Empty lists do not restrict anything, and
+/// rules without item kinds or selector, or with the `file` kind, are checked against whole
+/// files.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "RawScope")]
+pub struct RuleScope {
+    pub paths: Vec<String>,
+    pub exclude: Vec<String>,
+    pub languages: Vec<String>,
+    pub item_kinds: Vec<String>,
+    pub selector: Option<Selector>,
+    path_set: GlobSet,
+    exclude_set: GlobSet,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    Ok(builder.build()?)
+}
+
+impl TryFrom<RawScope> for RuleScope {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawScope) -> Result<Self> {
+        for kind in &raw.item_kinds {
+            if kind != "file" && !ITEM_KINDS.contains(&item_kind(kind)) {
+                bail!("Invalid item kind `{}`", kind);
+            }
+        }
+        // A rule is checked either against whole files or against items, never both.
+        if raw.item_kinds.len() > 1 && raw.item_kinds.iter().any(|kind| kind == "file") {
+            bail!(
+                "Invalid item kinds [{}]: `file` cannot be mixed with other kinds",
+                raw.item_kinds.join(", ")
+            );
+        }
+        Ok(Self {
+            path_set: glob_set(&raw.paths)?,
+            exclude_set: glob_set(&raw.exclude)?,
+            paths: raw.paths,
+            exclude: raw.exclude,
+            languages: raw.languages,
+            item_kinds: raw.item_kinds,
+            selector: raw.selector.as_deref().map(Selector::parse).transpose()?,
+        })
+    }
+}
+
+impl RuleScope {
+    /// Whether the rule applies to the file at `path`, written in `language`. Paths are
+    /// matched relative to the working directory.
+    pub fn matches_file(&self, path: &str, language: &str) -> bool {
+        let path = Path::new(path);
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let path = path
+            .strip_prefix(&cwd)
+            .or_else(|_| path.strip_prefix("."))
+            .unwrap_or(path);
+        (self.languages.is_empty() || self.languages.iter().any(|l| l == language))
+            && (self.paths.is_empty() || self.path_set.is_match(path))
+            && !self.exclude_set.is_match(path)
+    }
+
+    pub fn is_file_level(&self) -> bool {
+        (self.item_kinds.is_empty() && self.selector.is_none())
+            || self.item_kinds.iter().any(|kind| kind == "file")
+    }
+
+    pub fn matches_item(&self, item: &ItemInfo) -> bool {
+        (self.item_kinds.is_empty() || self.item_kinds.iter().any(|k| item_kind(k) == item.kind))
+            && self
+                .selector
+                .as_ref()
+                .is_none_or(|selector| selector.matches(item))
+    }
+}
+
+/// A rule that applies to files and items without annotations in the source, from
+/// `.melangerules` or from the `[[rules]]` of the config.
+#[derive(Debug, Clone)]
+pub struct ProjectRule {
+    pub rule: Rule,
+    pub scope: RuleScope,
+    /// Where the rule was declared, such as `crates/core/.melangerules:3`.
+    pub source: String,
+    /// Globs of the related files whose code is shown along with the checked code.
+    pub context: Vec<String>,
+    /// Whether the model can explore the crate with tool calls while checking the rule.
+    pub tools: bool,
+}
+
+impl ProjectRule {
+    /// A rule checked against every file.
+    pub fn new(rule: Rule, source: String) -> Self {
+        Self {
+            rule,
+            scope: RuleScope::default(),
+            source,
+            context: Vec::new(),
+            tools: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_scope() {
+        let scope = RuleScope::try_from(RawScope {
+            paths: vec!["src/**/*.rs".to_string()],
+            exclude: vec!["src/generated/**".to_string()],
+            languages: vec!["rust".to_string()],
+            item_kinds: vec!["fn".to_string(), "struct".to_string()],
+            selector: None,
+        })
+        .unwrap();
+        assert!(scope.matches_file("./src/engine/tokens.rs", "rust"));
+        assert!(!scope.matches_file("src/generated/api.rs", "rust"));
+        assert!(!scope.matches_file("tests/cli.rs", "rust"));
+        assert!(!scope.matches_file("src/lib.py", "python"));
+        let item = |kind: &str| ItemInfo {
+            kind: kind.to_string(),
+            ..Default::default()
+        };
+        assert!(scope.matches_item(&item("function")));
+        assert!(!scope.matches_item(&item("enum")));
+        assert!(!scope.is_file_level());
+        assert!(RuleScope::default().matches_file("tests/cli.rs", "rust"));
+
+        let kinds = |kinds: &[&str]| {
+            RuleScope::try_from(RawScope {
+                paths: Vec::new(),
+                exclude: Vec::new(),
+                languages: Vec::new(),
+                item_kinds: kinds.iter().map(|kind| kind.to_string()).collect(),
+                selector: None,
+            })
+        };
+        assert!(kinds(&["file"]).unwrap().is_file_level());
+        assert!(kinds(&["module", "impl"]).is_ok());
+        assert!(kinds(&["fn", "file"]).is_err());
+        assert!(kinds(&["function", "closure"]).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{Context, Result, bail};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::rules::scope::{ITEM_KINDS, item_kind};
+
+static SELECTOR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:(pub(?:\([^)]*\))?|private)\s+)?(?:(async)\s+)?(\w+|\*)((?:\s+with\s+\w+\([^)]*\))*)(?:\s+of\s+([\w:]+))?(?:\s+in\s+(\S+))?$",
+    )
+    .unwrap()
+});
+
+static WITH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"with\s+(\w+)\(([^)]*)\)").unwrap());
+
+/// What selectors know about an item: its kind and module path, as given by the parser, and
+/// the parts of its declaration they can filter on.
+#[derive(Debug, Clone, Default)]
+pub struct ItemInfo {
+    pub kind: String,
+    pub path: String,
+    /// `pub`, `pub(crate)`... or `private`.
+    pub visibility: String,
+    pub is_async: bool,
+    pub derives: Vec<String>,
+    /// Paths of the attributes other than `derive`, such as `test` or `tokio::main`.
+    pub attributes: Vec<String>,
+    /// Trait implemented by an impl block.
+    pub trait_name: Option<String>,
+}
+
+/// Selects items by their declaration, e.g. `pub async fn in engine::*`,
+/// `struct with derive(Serialize)` or `impl of Display`:
+///
+/// `[pub | pub(crate) | private] [async] <kind | *> [with derive(..)] [with attr(..)]
+/// [of <trait>] [in <module path>]`
+///
+/// `in` matches the items nested anywhere under a module, type or trait whose path matches
+/// the pattern, where `*` stands for one path segment and `**` for any number of them.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    source: String,
+    visibility: Option<String>,
+    is_async: bool,
+    kind: Option<String>,
+    derives: Vec<String>,
+    attributes: Vec<String>,
+    trait_name: Option<String>,
+    within: Option<GlobMatcher>,
+}
+
+fn last_segment(path: &str) -> &str {
+    path.rsplit("::").next().unwrap_or(path).trim()
+}
+
+fn names(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Selector {
+    pub fn parse(source: &str) -> Result<Self> {
+        let source = source.trim();
+        let caps = SELECTOR
+            .captures(source)
+            .with_context(|| format!("Invalid selector `{}`", source))?;
+        let mut derives = Vec::new();
+        let mut attributes = Vec::new();
+        for with in WITH.captures_iter(caps.get(4).map_or("", |m| m.as_str())) {
+            match &with[1] {
+                "derive" => derives.extend(names(&with[2])),
+                "attr" => attributes.extend(names(&with[2])),
+                other => bail!("Invalid selector `{}`: unknown `with {}`", source, other),
+            }
+        }
+        let kind = Some(&caps[3])
+            .filter(|kind| *kind != "*")
+            .map(|kind| item_kind(kind).to_string());
+        if let Some(kind) = &kind
+            && !ITEM_KINDS.contains(&kind.as_str())
+        {
+            bail!(
+                "Invalid selector `{}`: unknown item kind `{}`",
+                source,
+                kind
+            );
+        }
+        let within = caps
+            .get(6)
+            .map(|path| {
+                GlobBuilder::new(&path.as_str().replace("::", "/"))
+                    .literal_separator(true)
+                    .build()
+                    .map(|glob| glob.compile_matcher())
+            })
+            .transpose()?;
+        Ok(Self {
+            source: source.to_string(),
+            visibility: caps.get(1).map(|m| m.as_str().replace(' ', "")),
+            is_async: caps.get(2).is_some(),
+            kind,
+            derives,
+            attributes,
+            trait_name: caps.get(5).map(|m| m.as_str().to_string()),
+            within,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, item: &ItemInfo) -> bool {
+        self.kind.as_ref().is_none_or(|kind| *kind == item.kind)
+            && self
+                .visibility
+                .as_ref()
+                .is_none_or(|visibility| *visibility == item.visibility)
+            && (!self.is_async || item.is_async)
+            && self
+                .derives
+                .iter()
+                .all(|derive| item.derives.iter().any(|d| last_segment(d) == derive))
+            && self
+                .attributes
+                .iter()
+                .all(|attr| item.attributes.iter().any(|a| a == attr))
+            && self.trait_name.as_ref().is_none_or(|name| {
+                item.trait_name
+                    .as_ref()
+                    .is_some_and(|t| last_segment(t) == last_segment(name))
+            })
+            && self.within.as_ref().is_none_or(|within| {
+                let segments: Vec<_> = item.path.split("::").collect();
+                (1..segments.len()).any(|len| within.is_match(segments[..len].join("/")))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(kind: &str, path: &str, visibility: &str) -> ItemInfo {
+        ItemInfo {
+            kind: kind.to_string(),
+            path: path.to_string(),
+            visibility: visibility.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_selectors() {
+        let selector = Selector::parse("pub async fn in engine::*").unwrap();
+        let mut query = item("function", "engine::llm_engine::LlmEngine::query", "pub");
+        assert!(!selector.matches(&query));
+        query.is_async = true;
+        assert!(selector.matches(&query));
+        assert!(!selector.matches(&ItemInfo {
+            path: "engine::query".to_string(),
+            ..query.clone()
+        }));
+
+        let selector = Selector::parse("struct with derive(Serialize, Debug)").unwrap();
+        let mut usage = item("struct", "engine::usage::Usage", "pub");
+        usage.derives = vec!["Debug".to_string(), "serde::Serialize".to_string()];
+        assert!(selector.matches(&usage));
+        usage.derives.pop();
+        assert!(!selector.matches(&usage));
+
+        let selector = Selector::parse("impl of Display").unwrap();
+        let mut display = item("impl", "rules::generic::Rule", "private");
+        assert!(!selector.matches(&display));
+        display.trait_name = Some("std::fmt::Display".to_string());
+        assert!(selector.matches(&display));
+
+        assert!(Selector::parse("pub fn with doc(x)").is_err());
+        assert!(Selector::parse("fn in").is_err());
+        assert!(Selector::parse("pub").is_err());
+    }
+}
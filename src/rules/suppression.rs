@@ -0,0 +1,273 @@
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
+
+use crate::rules::generic::{Rule, RuleWithCode, is_rule_comment, normalize};
+
+static SUPPRESSION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*// *melange-allow(-file)?\((.*)$").unwrap());
+
+/// A `// melange-allow(<rule>): <reason>` comment, silencing a rule on the item below it, or
+/// a `// melange-allow-file(<rule>): <reason>` comment, silencing it in the whole file.
+/// `<rule>` is the id of the rule or a prefix of its text.
+#[derive(Debug, Clone, Serialize)]
+pub struct Suppression {
+    pub file_name: String,
+    pub line: usize,
+    pub pattern: String,
+    pub reason: String,
+    pub file_level: bool,
+}
+
+impl Suppression {
+    /// Whether the suppression silences `rule`. A suppression naming no rule silences none.
+    pub fn matches(&self, rule: &Rule) -> bool {
+        let pattern = normalize(&self.pattern);
+        !pattern.is_empty()
+            && (self.pattern == rule.id() || normalize(rule.description()).starts_with(&pattern))
+    }
+
+    /// Whether the suppression names a rule and gives a reason, without which it is ignored.
+    fn is_valid(&self) -> bool {
+        !normalize(&self.pattern).is_empty() && !self.reason.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SuppressionReport {
+    /// Number of rule checks skipped because of a suppression.
+    pub suppressed: usize,
+    /// Suppressions that match none of the rules they apply to.
+    pub stale: Vec<Suppression>,
+    /// Suppressions ignored because they do not give a reason.
+    pub missing_reason: Vec<Suppression>,
+    /// Suppressions ignored because they do not name a rule, as in `melange-allow(): <reason>`.
+    pub missing_rule: Vec<Suppression>,
+}
+
+impl SuppressionReport {
+    pub fn extend(&mut self, other: SuppressionReport) {
+        self.suppressed += other.suppressed;
+        self.stale.extend(other.stale);
+        self.missing_reason.extend(other.missing_reason);
+        self.missing_rule.extend(other.missing_rule);
+    }
+
+    /// The suppressions ignored for want of a rule or a reason and the stale ones, with what is
+    /// wrong with each of them.
+    pub fn warnings(&self) -> Vec<(&Suppression, String)> {
+        let missing_rule = self.missing_rule.iter().map(|suppression| {
+            (
+                suppression,
+                "suppression ignored, it names no rule".to_string(),
+            )
+        });
+        let missing_reason = self.missing_reason.iter().map(|suppression| {
+            let warning = format!(
+                "suppression of \"{}\" ignored, it needs a reason",
+                suppression.pattern
+            );
+            (suppression, warning)
+        });
+        let stale = self.stale.iter().map(|suppression| {
+            let warning = format!(
+                "stale suppression, \"{}\" matches no rule",
+                suppression.pattern
+            );
+            (suppression, warning)
+        });
+        missing_rule.chain(missing_reason).chain(stale).collect()
+    }
+
+    pub fn print(&self) {
+        if self.suppressed > 0 {
+            println!("melange: {} rule checks suppressed", self.suppressed);
+        }
+        self.print_warnings();
+    }
+
+    pub fn print_warnings(&self) {
+        for (suppression, warning) in self.warnings() {
+            eprintln!(
+                "melange: {}:{} - {}",
+                suppression.file_name, suppression.line, warning
+            );
+        }
+    }
+}
+
+pub fn is_suppression(line: &str) -> bool {
+    SUPPRESSION.is_match(line)
+}
+
+/// Splits what follows `melange-allow(` into the rule and the reason, at the first `)` closing
+/// that parenthesis and followed by `:` or by the end of the line, so that the rule text can hold
+/// parentheses of its own.
+fn split_rule(rest: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (idx, c) in rest.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ')' => {
+                let after = rest[idx + 1..].trim();
+                if after.is_empty() {
+                    return Some((&rest[..idx], ""));
+                }
+                if let Some(reason) = after.strip_prefix(':') {
+                    return Some((&rest[..idx], reason));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Item suppressions by the line of the item they apply to, and file suppressions.
+fn extract_suppressions(
+    file_name: &str,
+    content: &str,
+) -> (HashMap<usize, Vec<Suppression>>, Vec<Suppression>) {
+    let mut items = HashMap::new();
+    let mut file = Vec::new();
+    let mut pending = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if let Some(caps) = SUPPRESSION.captures(line)
+            && let Some((pattern, reason)) = split_rule(&caps[2])
+        {
+            let suppression = Suppression {
+                file_name: file_name.to_string(),
+                line: i + 1,
+                pattern: pattern.trim().to_string(),
+                reason: reason.trim().to_string(),
+                file_level: caps.get(1).is_some(),
+            };
+            if suppression.file_level {
+                file.push(suppression);
+            } else {
+                pending.push(suppression);
+            }
+        } else if !pending.is_empty() && !is_rule_comment(line) {
+            items.insert(i + 1, std::mem::take(&mut pending));
+        }
+    }
+    (items, file)
+}
+
+/// Drops the rule checks of `file_name`, whose code is `content`, silenced by its suppression
+/// comments.
+pub fn apply_suppressions(
+    file_name: &str,
+    content: &str,
+    rules: Vec<RuleWithCode>,
+) -> (Vec<RuleWithCode>, SuppressionReport) {
+    let (items, file) = extract_suppressions(file_name, content);
+    let all = || items.values().flatten().chain(&file);
+    let mut report = SuppressionReport {
+        missing_rule: all()
+            .filter(|s| normalize(&s.pattern).is_empty())
+            .cloned()
+            .collect(),
+        missing_reason: all()
+            .filter(|s| !normalize(&s.pattern).is_empty() && s.reason.is_empty())
+            .cloned()
+            .collect(),
+        ..Default::default()
+    };
+
+    // Lines of the suppressions that silenced at least one rule check.
+    let mut used = HashSet::new();
+    let mut kept = Vec::new();
+    for rule in rules {
+        let item_suppressions = match items.get(&rule.line_at(rule.item_range().start)) {
+            Some(item) if rule.item_kind() != "file" => item.as_slice(),
+            _ => &[],
+        };
+        let matching: Vec<_> = item_suppressions
+            .iter()
+            .chain(&file)
+            .filter(|s| s.is_valid() && rule.rules().iter().all(|r| s.matches(r)))
+            .map(|s| s.line)
+            .collect();
+        if matching.is_empty() {
+            kept.push(rule);
+        } else {
+            used.extend(matching);
+            report.suppressed += 1;
+        }
+    }
+    report.stale = all()
+        .filter(|s| s.is_valid() && !used.contains(&s.line))
+        .cloned()
+        .collect();
+    report.stale.sort_by_key(|s| s.line);
+    (kept, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_suppressions() {
+        let content = "\
+// melange-allow-file(No blocking IO): this is a sync crate
+// #AIRULE: functions should have a doc comment
+// melange-allow(functions should): trivial helper
+// melange-allow(enum names):
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+// melange-allow(use Result (not panics): in fn(): errors): fallible on purpose
+// melange-allow(no unwrap) : see parse(): it cannot fail
+fn parse() {}
+";
+        let (items, file) = extract_suppressions("a.rs", content);
+        assert_eq!(file.len(), 1);
+        assert_eq!(file[0].reason, "this is a sync crate");
+        let item = &items[&5];
+        assert_eq!(item.len(), 2);
+        assert!(item[0].matches(&Rule::new("Functions  should have a doc comment")));
+        assert!(item[1].reason.is_empty());
+        assert!(!item[1].matches(&Rule::new("functions should have a doc comment")));
+
+        // The rule text runs up to the parenthesis closing it.
+        let item = &items[&10];
+        assert_eq!(item[0].pattern, "use Result (not panics): in fn(): errors");
+        assert_eq!(item[0].reason, "fallible on purpose");
+        assert_eq!(item[1].pattern, "no unwrap");
+        assert_eq!(item[1].reason, "see parse(): it cannot fail");
+    }
+
+    #[test]
+    fn test_suppressions_without_rule() {
+        let content = "\
+// melange-allow-file(): silence everything
+// melange-allow( ): no panics here
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+";
+        let start = content.find("fn add").unwrap();
+        let rule = RuleWithCode::new(
+            Rule::new("no panics"),
+            "a.rs".to_string(),
+            std::sync::Arc::new(content.to_string()),
+            "function".to_string(),
+            "add".to_string(),
+            start..content.len() - 1,
+        );
+        // Neither comment names a rule, so the check is kept and both are reported.
+        let (kept, report) = apply_suppressions("a.rs", content, vec![rule]);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(report.suppressed, 0);
+        let mut lines: Vec<_> = report.missing_rule.iter().map(|s| s.line).collect();
+        lines.sort();
+        assert_eq!(lines, [1, 2]);
+        assert!(report.missing_reason.is_empty() && report.stale.is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, ops::Range};
+
+use crate::{
+    engine::cache::stable_hash,
+    rules::generic::{RuleWithCode, Severity, line_at, normalize_path},
+};
+
+/// A rule violation reported by the model, located in the original file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Violation {
+    pub rule: String,
+    pub rule_id: String,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Stable key of the violation across commits, see [`fingerprint`].
+    pub fingerprint: String,
+    pub file_name: String,
+    pub item_kind: String,
+    pub item_name: String,
+    /// Module path of the item, such as `cakes::MyCake`.
+    pub item_path: String,
+    /// Line where the violating item starts, attributes included.
+    pub item_line: usize,
+    pub line: usize,
+    pub byte_offset: usize,
+    pub quote: String,
+    pub explanation: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<Fix>,
+}
+
+/// An edit suggested by the model: `replacement` takes the place of the quoted code.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fix {
+    pub byte_range: Range<usize>,
+    pub replacement: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "melange: {}:{} - {}: rule \"{}\" [{}] violation",
+            self.file_name, self.line, self.severity, self.rule, self.rule_id
+        )?;
+        writeln!(f, "{}", self.quote)?;
+        write!(f, "{}", self.explanation)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\nSuggested fix:\n{}", fix.replacement)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Violation {
+    /// A violation of rule `rule_id` in function `add` of `file_name`, quoting `quote` where it
+    /// first appears in `content`.
+    pub fn test(rule_id: &str, file_name: &str, content: &str, quote: &str) -> Self {
+        let byte_offset = content.find(quote).expect("the quote is in the content");
+        Self {
+            rule: rule_id.replace('-', " "),
+            rule_id: rule_id.to_string(),
+            severity: Severity::default(),
+            fingerprint: fingerprint(rule_id, file_name, "function", "add", quote),
+            file_name: file_name.to_string(),
+            item_kind: "function".to_string(),
+            item_name: "add".to_string(),
+            item_path: "add".to_string(),
+            item_line: 1,
+            line: line_at(content, byte_offset),
+            byte_offset,
+            quote: quote.to_string(),
+            explanation: String::new(),
+            fix: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawViolation {
+    // Number of the violated rule, for prompts carrying several rules.
+    #[serde(default)]
+    rule: Option<usize>,
+    quote: String,
+    explanation: String,
+    #[serde(default)]
+    replacement: Option<String>,
+    // Path of the related file the quote comes from, when it is not the checked code.
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawResponse {
+    violations: Vec<RawViolation>,
+}
+
+/// Extracts the json object from a model response, skipping any markdown fences or chatter.
+fn json_body(response: &str) -> Option<&str> {
+    let start = response.find('{')?;
+    let end = response.rfind('}')?;
+    (start < end).then(|| &response[start..=end])
+}
+
+/// Finds `quote` in `code`, falling back to a whitespace-insensitive match on its first line.
+fn locate(code: &str, quote: &str) -> Option<usize> {
+    let quote = quote.trim();
+    if quote.is_empty() {
+        return None;
+    }
+    if let Some(idx) = code.find(quote) {
+        return Some(idx);
+    }
+    let first_line = quote.lines().next()?.trim();
+    let squashed: String = first_line.split_whitespace().collect();
+    code.match_indices(first_line.split_whitespace().next()?)
+        .map(|(idx, _)| idx)
+        .find(|&idx| {
+            code[idx..]
+                .split_whitespace()
+                .collect::<String>()
+                .starts_with(&squashed)
+        })
+}
+
+/// Identifies a violation by its rule, file, item and quoted code, leaving out line numbers so
+/// that it survives the code moving around in the file.
+pub fn fingerprint(
+    rule_id: &str,
+    file_name: &str,
+    item_kind: &str,
+    item_path: &str,
+    quote: &str,
+) -> String {
+    let snippet = quote.split_whitespace().collect::<Vec<_>>().join(" ");
+    stable_hash(&[
+        rule_id,
+        normalize_path(file_name),
+        item_kind,
+        item_path,
+        &snippet,
+    ])
+}
+
+/// Parses the model response for `rule` into violations with offsets relative to the whole file.
+pub fn parse_response(response: &str, rule: &RuleWithCode) -> Result<Vec<Violation>> {
+    let body = json_body(response).context("No json object in response")?;
+    let raw: RawResponse = serde_json::from_str(body).context("Malformed violations json")?;
+    Ok(raw
+        .violations
+        .into_iter()
+        .filter_map(|raw| {
+            // Violations in related files are searched for in the whole file, and belong to it.
+            let context = raw.path.as_deref().and_then(|path| {
+                let path = path.trim().trim_start_matches("./");
+                rule.context().iter().find(|file| file.path == path)
+            });
+            let (file_name, content, start, code) = match context {
+                Some(file) => (
+                    file.path.as_str(),
+                    file.content.as_str(),
+                    0,
+                    file.content.as_str(),
+                ),
+                None => (
+                    rule.file_name(),
+                    rule.file_content(),
+                    rule.byte_range().start,
+                    rule.get_code_block(),
+                ),
+            };
+            // The header shown ahead of a chunk is part of the item, and its quotes are too.
+            let header = rule.header_range().filter(|_| context.is_none());
+            let byte_offset = match locate(code, &raw.quote) {
+                Some(idx) => start + idx,
+                None => header
+                    .and_then(|header| {
+                        locate(&content[header.clone()], &raw.quote).map(|idx| header.start + idx)
+                    })
+                    .unwrap_or(start),
+            };
+            let rules = rule.rules();
+            let violated = match raw.rule {
+                None => &rules[0],
+                Some(n) => match rules.get(n.wrapping_sub(1)) {
+                    Some(violated) => violated,
+                    None => {
+                        // Attributing it to another rule would report a violation of the wrong one.
+                        warn!(
+                            "Dropping a violation of rule {} in {}, which has {} rules: {}",
+                            n,
+                            rule.item_path(),
+                            rules.len(),
+                            raw.quote.trim()
+                        );
+                        return None;
+                    }
+                },
+            };
+            // A fix needs the exact span it replaces, so only verbatim quotes get one.
+            let fix = raw.replacement.and_then(|replacement| {
+                let quote = raw.quote.trim();
+                let idx = code.find(quote).filter(|_| !quote.is_empty())?;
+                Some(Fix {
+                    byte_range: start + idx..start + idx + quote.len(),
+                    replacement,
+                })
+            });
+            let line = line_at(content, byte_offset);
+            Some(Violation {
+                rule: violated.to_string(),
+                rule_id: violated.id().to_string(),
+                severity: violated.severity(),
+                fingerprint: fingerprint(
+                    violated.id(),
+                    file_name,
+                    rule.item_kind(),
+                    rule.item_path(),
+                    &raw.quote,
+                ),
+                file_name: file_name.to_string(),
+                item_kind: rule.item_kind().to_string(),
+                item_name: rule.item_name().to_string(),
+                item_path: rule.item_path().to_string(),
+                item_line: match context {
+                    Some(_) => line,
+                    None => rule.line_at(rule.item_range().start),
+                },
+                line,
+                byte_offset,
+                quote: raw.quote,
+                explanation: raw.explanation,
+                fix,
+            })
+        })
+        .collect())
+}
+
+/// Merges violations coming from the chunks of one item, dropping duplicates.
+pub fn merge_violations(chunks: Vec<Vec<Violation>>) -> Vec<Violation> {
+    let mut merged: Vec<Violation> = Vec::new();
+    for violation in chunks.into_iter().flatten() {
+        if !merged.contains(&violation) {
+            merged.push(violation);
+        }
+    }
+    merged.sort_by_key(|v| v.byte_offset);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::generic::{ContextFile, Rule};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_response_offsets() {
+        let content = "fn a() {}\n\nenum Cake {\n    Frosting,\n    RlCherry,\n}\n";
+        let start = content.find("enum").unwrap();
+        let rule = RuleWithCode::new(
+            Rule::new("enum variants should be one-word only"),
+            "cake.rs".to_string(),
+            Arc::new(content.to_string()),
+            "enum".to_string(),
+            "Cake".to_string(),
+            start..content.len() - 1,
+        );
+        let response = r#"```json
+{"violations": [{"quote": "RlCherry,", "explanation": "two words"}]}
+```"#;
+        let violations = parse_response(response, &rule).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].byte_offset, content.find("RlCherry").unwrap());
+        assert_eq!(violations[0].line, 5);
+
+        let moved = format!("\n\n{}", content);
+        let rule = RuleWithCode::new(
+            Rule::new("enum variants should be one-word only"),
+            "cake.rs".to_string(),
+            Arc::new(moved.clone()),
+            "enum".to_string(),
+            "Cake".to_string(),
+            start + 2..moved.len() - 1,
+        );
+        let moved = parse_response(response, &rule).unwrap();
+        assert_eq!(moved[0].line, 7);
+        assert_eq!(moved[0].fingerprint, violations[0].fingerprint);
+
+        let tests = Arc::new("#[test]\nfn test_frosting() {}\n".to_string());
+        let rule = rule.with_context(vec![ContextFile::whole(
+            "tests/cake.rs".to_string(),
+            Arc::clone(&tests),
+        )]);
+        let response = r#"{"violations": [{"path": "./tests/cake.rs", "quote": "fn test_frosting", "explanation": "no assertion"}]}"#;
+        let violations = parse_response(response, &rule).unwrap();
+        assert_eq!(violations[0].file_name, "tests/cake.rs");
+        assert_eq!(violations[0].line, 2);
+    }
+
+    #[test]
+    fn test_parse_batched_response() {
+        let content =
+            Arc::new("fn first(v: &[i32]) -> i32 {\n    *v.first().unwrap()\n}\n".to_string());
+        let check = |rule: Rule| {
+            RuleWithCode::new(
+                rule,
+                "first.rs".to_string(),
+                Arc::clone(&content),
+                "function".to_string(),
+                "first".to_string(),
+                0..content.len() - 1,
+            )
+        };
+        let batches = RuleWithCode::batch(
+            vec![
+                check(Rule::with_id("no-panics", "no panics")),
+                check(Rule::with_id("documented", "document functions")),
+            ],
+            |_| false,
+        );
+        let rule = &batches[0];
+        assert_eq!(rule.rules().len(), 2);
+        let response = r#"{"violations": [
+            {"rule": 2, "quote": "fn first", "explanation": "no doc comment"},
+            {"rule": 1, "quote": "unwrap()", "explanation": "panics on empty slices"},
+            {"rule": 3, "quote": "*v", "explanation": "no such rule"},
+            {"quote": "v.first()", "explanation": "unnumbered"}
+        ]}"#;
+        let violations = parse_response(response, rule).unwrap();
+        let found: Vec<_> = violations
+            .iter()
+            .map(|v| (v.rule_id.as_str(), v.line))
+            .collect();
+        // The violation of a rule the batch does not have is dropped, and an unnumbered one
+        // goes to the first rule.
+        assert_eq!(
+            found,
+            [("documented", 1), ("no-panics", 2), ("no-panics", 2)]
+        );
+    }
+}